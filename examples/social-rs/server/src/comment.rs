@@ -6,11 +6,11 @@ use serde::{Deserialize, Serialize};
 #[collection(comments)]
 pub struct Comment {
     #[owned_by(users, user_id)]
-    pub commented_by: u32,
+    pub commented_by: Option<u32>,
     #[index]
     pub comment_id: u32,
     pub text: String,
     #[owned_by(posts, post_id)]
-    pub parent_post: u32,
+    pub parent_post: Option<u32>,
     pub date: String,
 }
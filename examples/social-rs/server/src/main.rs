@@ -7,10 +7,9 @@ use actix_web::{delete, get, post, web, App, HttpResponse, HttpServer, Responder
 use comment::Comment;
 use dotenv::dotenv;
 use futures::StreamExt;
-use mongowner::delete::safe_delete;
 use mongowner::mongo::bson::doc;
 use mongowner::mongo::{Client, Collection, Database};
-use mongowner::Schemable;
+use mongowner::{Mongowner, Schemable};
 use post::Post;
 use user::User;
 
@@ -43,69 +42,36 @@ async fn clear_posts(client: web::Data<Client>) -> HttpResponse {
 }
 
 #[delete("/delete_post/{post_id}")]
-async fn delete_post(client: web::Data<Client>, post_id: web::Path<String>) -> HttpResponse {
+async fn delete_post(mongowner: web::Data<Mongowner>, post_id: web::Path<u32>) -> HttpResponse {
     let post_id = post_id.into_inner();
-    let database = client.database(DB_NAME);
-    let collection: Collection<Post> = database.collection(Post::collection_name());
-    let post = match collection
-        .find_one(
-            doc! { "post_id": post_id.clone().to_string().parse::<i32>().unwrap() },
-            None,
-        )
-        .await
-    {
-        Ok(Some(post)) => post,
-        Ok(None) => {
-            return HttpResponse::NotFound().body(format!("No post found with post_id {post_id}"))
-        }
-        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
-    };
-    let result = safe_delete(post, &database).await;
-    HttpResponse::Ok().body("Post deletion successful")
+    match mongowner.delete_by_index::<Post>(post_id).await {
+        Ok(Some(_)) => HttpResponse::Ok().body("Post deletion successful"),
+        Ok(None) => HttpResponse::NotFound().body(format!("No post found with post_id {post_id}")),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
 }
 
 #[delete("/delete_user/{user_id}")]
-async fn delete_user(client: web::Data<Client>, user_id: web::Path<String>) -> HttpResponse {
+async fn delete_user(mongowner: web::Data<Mongowner>, user_id: web::Path<u32>) -> HttpResponse {
     let user_id = user_id.into_inner();
-    let database = client.database(DB_NAME);
-    let collection: Collection<User> = database.collection(User::collection_name());
-    let user = match collection
-        .find_one(
-            doc! { "user_id": user_id.clone().to_string().parse::<i32>().unwrap() },
-            None,
-        )
-        .await
-    {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return HttpResponse::NotFound().body(format!("No user found with user_id {user_id}"))
-        }
-        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
-    };
-    let result = safe_delete(user, &database).await;
-    HttpResponse::Ok().body("User deletion successful")
+    match mongowner.delete_by_index::<User>(user_id).await {
+        Ok(Some(_)) => HttpResponse::Ok().body("User deletion successful"),
+        Ok(None) => HttpResponse::NotFound().body(format!("No user found with user_id {user_id}")),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
 }
 
 #[delete("/delete_comment/{comment_id}")]
-async fn delete_comment(client: web::Data<Client>, comment_id: web::Path<String>) -> HttpResponse {
+async fn delete_comment(
+    mongowner: web::Data<Mongowner>,
+    comment_id: web::Path<u32>,
+) -> HttpResponse {
     let comment_id = comment_id.into_inner();
-    let database = client.database(DB_NAME);
-    let collection: Collection<Comment> = database.collection(Comment::collection_name());
-    let comment = match collection
-        .find_one(
-            doc! { "comment_id": comment_id.clone().to_string().parse::<i32>().unwrap() },
-            None,
-        )
-        .await
-    {
-        Ok(Some(comment)) => comment,
-        Ok(None) => {
-            return HttpResponse::NotFound().body(format!("No comment found with id {comment_id}"))
-        }
-        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
-    };
-    let result = safe_delete(comment, &database).await;
-    HttpResponse::Ok().body("Comment deletion successful")
+    match mongowner.delete_by_index::<Comment>(comment_id).await {
+        Ok(Some(_)) => HttpResponse::Ok().body("Comment deletion successful"),
+        Ok(None) => HttpResponse::NotFound().body(format!("No comment found with id {comment_id}")),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
 }
 
 #[get("/get_all_users")]
@@ -305,6 +271,8 @@ async fn main() -> std::io::Result<()> {
 
     let uri = std::env::var("MONGOURI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
     let client = Client::with_uri_str(uri).await.expect("failed to connect");
+    let mongowner =
+        Mongowner::new(client.clone(), DB_NAME).expect("failed to load ownership graph");
 
     let user = User {
         user_id: 2, // temporarily using a u8 here
@@ -341,6 +309,7 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .app_data(web::Data::new(client.clone()))
+            .app_data(web::Data::new(mongowner.clone()))
             .service(add_user)
             .service(add_post)
             .service(add_comment)
@@ -0,0 +1,486 @@
+use petgraph::{algo::toposort, graphmap, visit::EdgeFiltered, Directed};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    env, fmt, fs,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+/// Represents an edge between two structs.
+/// Ex. for User, Post, we would have owner_index = user_id, owned_field = posted_by
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct OwnEdge<'a> {
+    owner_index: &'a str,
+    owned_field: &'a str,
+    kind: EdgeKind,
+}
+
+/// The relation an edge stands for, as in mongowner's `EdgeKind`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EdgeKind {
+    #[default]
+    Owned,
+    References(OnDelete),
+}
+
+/// The `on_delete` action of a `#[references]` annotation, as in mongowner's `OnDelete`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnDelete {
+    Restrict,
+    SetNull,
+    Cascade,
+}
+
+impl EdgeKind {
+    /// Whether deletions cascade along edges of this kind, which must not form a cycle.
+    fn cascades(&self) -> bool {
+        matches!(
+            self,
+            EdgeKind::Owned | EdgeKind::References(OnDelete::Cascade)
+        )
+    }
+}
+
+/// The ownership details of a single struct deriving `Schema`. Each struct writes its own
+/// fragment file, and the graph and index map are assembled from the fragments, so neither
+/// depends on the order in which structs are compiled.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fragment {
+    pub struct_name: String,
+    pub collection: String,
+    pub index: Vec<String>,
+    pub owned_by: Vec<FragmentEdge>,
+    #[serde(default)]
+    pub embedded: Vec<FragmentEmbedded>,
+    /// The source file the struct is declared in, if the compiler knows it.
+    #[serde(default)]
+    pub source_file: Option<PathBuf>,
+    /// The `session` of the compiler process that expanded the struct.
+    #[serde(default)]
+    pub session: String,
+}
+
+/// An `#[owned_by(owner, owner_index)]` annotation on `owned_field`, or a
+/// `#[references(owner, owner_index, on_delete = _)]` one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FragmentEdge {
+    pub owner: String,
+    pub owner_index: String,
+    pub owned_field: String,
+    #[serde(default)]
+    pub kind: EdgeKind,
+}
+
+/// An `#[embedded_owned_by(owner_collection, owner_index)]` annotation on `array_field`, as
+/// in mongowner's `EmbeddedOwnedBy`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FragmentEmbedded {
+    pub owner_collection: String,
+    pub owner_index: String,
+    pub array_field: String,
+    pub element_field: Option<String>,
+}
+
+/// The ownership relations of the crate (including references that cascade) form a cycle:
+/// each collection of `path` is owned by the next one, and the last one is the first.
+#[derive(Debug)]
+pub struct CycleError {
+    pub path: Vec<String>,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "owned_by annotations form a cycle in the ownership graph: {} (each collection is \
+            owned by the next)",
+            self.path.join(" -> ")
+        )
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+/// Another struct of the crate, `struct_name`, is already stored in `collection`.
+#[derive(Debug)]
+pub struct DuplicateCollectionError {
+    pub collection: String,
+    pub struct_name: String,
+}
+
+impl fmt::Display for DuplicateCollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the collection {} is already stored by the struct {}; each collection must be \
+            derived by a single struct",
+            self.collection, self.struct_name
+        )
+    }
+}
+
+impl std::error::Error for DuplicateCollectionError {}
+
+/// Identifies the compiler process expanding derives: a crate's derives are all expanded by
+/// the same process each time it is compiled, though one process (e.g. rust-analyzer's) may
+/// expand them any number of times.
+pub fn session() -> &'static str {
+    static SESSION: OnceLock<String> = OnceLock::new();
+    SESSION.get_or_init(|| {
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("{}-{}", std::process::id(), started.as_nanos())
+    })
+}
+
+/// Records `fragment` for the crate being compiled and rewrites that crate's graph.json,
+/// index_map.json and embedded.json from all of its fragments.
+pub fn write_fragment(fragment: &Fragment) -> Result<(), Box<dyn std::error::Error>> {
+    write_fragment_in(&crate_dir()?, fragment)
+}
+
+/// `write_fragment` for the crate whose fragments are kept in `dir`.
+///
+/// Fragments whose source file was removed, or modified since they were written, are
+/// removed first: the derives of a modified file are expanded again, so structs (or
+/// annotations) that were removed from it leave nothing behind, whichever process expands
+/// them. A lock file serializes compilers writing to the same directory, and files are
+/// replaced by renaming so that they are never seen half written.
+///
+/// Fails with a `DuplicateCollectionError` if another struct expanded in the same session is
+/// stored in the collection of `fragment`, and with a `CycleError` if its edges close a cycle
+/// of deletions. Fragments of other sessions aren't checked, since they may come from files
+/// that are no longer part of the crate.
+fn write_fragment_in(dir: &Path, fragment: &Fragment) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let lock = File::create(dir.join(".lock"))?;
+    lock.lock()?;
+
+    for fragment_path in fragment_paths(dir)? {
+        if is_stale(&fragment_path)? {
+            fs::remove_file(fragment_path)?;
+        }
+    }
+    for fragment_path in fragment_paths(dir)? {
+        let other: Fragment = serde_json::from_str(&fs::read_to_string(fragment_path)?)?;
+        if other.session == fragment.session
+            && other.collection == fragment.collection
+            && other.struct_name != fragment.struct_name
+        {
+            return Err(Box::new(DuplicateCollectionError {
+                collection: other.collection,
+                struct_name: other.struct_name,
+            }));
+        }
+    }
+    let fragment_name = format!(
+        "{}-{}.fragment.json",
+        fragment.collection, fragment.struct_name
+    );
+    write_atomically(&dir.join(fragment_name), &serde_json::to_string(fragment)?)?;
+
+    let mut fragments = Vec::new();
+    for fragment_path in fragment_paths(dir)? {
+        let fragment: Fragment = serde_json::from_str(&fs::read_to_string(fragment_path)?)?;
+        fragments.push(fragment);
+    }
+
+    // Check that the edges of this fragment don't introduce a cycle of deletions; other
+    // references may point anywhere
+    let session_graph = build_graph(
+        fragments
+            .iter()
+            .filter(|other| other.session == fragment.session),
+    );
+    let cascading = EdgeFiltered::from_fn(&session_graph, |(_, _, edge): (_, _, &OwnEdge)| {
+        edge.kind.cascades()
+    });
+    if let Err(cycle) = toposort(&cascading, None) {
+        return Err(Box::new(CycleError {
+            path: cycle_through(&session_graph, cycle.node_id()),
+        }));
+    }
+
+    let graph = build_graph(fragments.iter());
+    let index_map: BTreeMap<&String, &Vec<String>> = fragments
+        .iter()
+        .map(|fragment| (&fragment.collection, &fragment.index))
+        .collect();
+    let graph_name = env::var("GRAPH_NAME").unwrap_or("graph.json".to_string());
+    write_atomically(&dir.join(graph_name), &serde_json::to_string(&graph)?)?;
+    let index_name = env::var("INDEX_NAME").unwrap_or("index_map.json".to_string());
+    write_atomically(&dir.join(index_name), &serde_json::to_string(&index_map)?)?;
+    // The embedded annotations, with the collection holding each array, as mongowner's
+    // `load_embedded_file` reads them
+    let embedded: Vec<(&String, &FragmentEmbedded)> = fragments
+        .iter()
+        .flat_map(|fragment| {
+            fragment
+                .embedded
+                .iter()
+                .map(|embedded| (&fragment.collection, embedded))
+        })
+        .collect();
+    write_atomically(
+        &dir.join("embedded.json"),
+        &serde_json::to_string(&embedded)?,
+    )?;
+
+    Ok(())
+}
+
+/// The ownership graph of `fragments`, with an edge from each collection to each of its
+/// owners.
+fn build_graph<'a>(
+    fragments: impl Iterator<Item = &'a Fragment>,
+) -> graphmap::GraphMap<&'a str, OwnEdge<'a>, Directed> {
+    let mut graph = graphmap::GraphMap::new();
+    for fragment in fragments {
+        graph.add_node(fragment.collection.as_str());
+        for edge in &fragment.owned_by {
+            graph.add_edge(
+                fragment.collection.as_str(),
+                edge.owner.as_str(),
+                OwnEdge {
+                    owner_index: &edge.owner_index,
+                    owned_field: &edge.owned_field,
+                    kind: edge.kind,
+                },
+            );
+        }
+    }
+    graph
+}
+
+/// The directory holding the fragments and graph of the crate being compiled:
+/// {CARGO_MANIFEST_DIR}/target/mongowner/{CARGO_CRATE_NAME}. Crates of the same package
+/// (e.g. a library and its integration tests) each get their own graph.
+fn crate_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR")?;
+    let crate_name = env::var("CARGO_CRATE_NAME").unwrap_or("unknown".to_string());
+    Ok(Path::new(&manifest_dir)
+        .join("target")
+        .join("mongowner")
+        .join(crate_name))
+}
+
+/// Whether the fragment at `path` was written before the last change to its source file, its
+/// source file no longer exists, or it cannot be read. Fragments of unknown source are only
+/// ever replaced.
+fn is_stale(path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let fragment: Fragment = match serde_json::from_str(&fs::read_to_string(path)?) {
+        Ok(fragment) => fragment,
+        Err(_) => return Ok(true),
+    };
+    let source_file = match fragment.source_file {
+        Some(source_file) => source_file,
+        None => return Ok(false),
+    };
+    let source_modified = match fs::metadata(source_file) {
+        Ok(metadata) => metadata.modified()?,
+        Err(_) => return Ok(true),
+    };
+    Ok(fs::metadata(path)?.modified()? < source_modified)
+}
+
+/// The fragment files in `dir`, sorted by name.
+fn fragment_paths(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".fragment.json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Replaces the file at `path` with `contents` by writing a temporary file next to it and
+/// renaming it into place.
+fn write_atomically(path: &Path, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// The collections of the shortest cycle of cascading edges through `start`, beginning and
+/// ending with it.
+fn cycle_through(graph: &graphmap::GraphMap<&str, OwnEdge, Directed>, start: &str) -> Vec<String> {
+    // Breadth-first search along owner edges, remembering how each collection was reached
+    let mut reached_from: HashMap<&str, &str> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(collection) = queue.pop_front() {
+        let owners = graph
+            .edges(collection)
+            .filter(|(_, _, edge)| edge.kind.cascades())
+            .map(|(_, owner, _)| owner);
+        for owner in owners {
+            if reached_from.contains_key(owner) {
+                continue;
+            }
+            reached_from.insert(owner, collection);
+            if owner == start {
+                queue.clear();
+                break;
+            }
+            queue.push_back(owner);
+        }
+    }
+
+    let mut path = vec![start.to_string()];
+    let mut collection = start;
+    while let Some(&previous) = reached_from.get(collection) {
+        path.push(previous.to_string());
+        if previous == start {
+            break;
+        }
+        collection = previous;
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    // a fresh directory for the fragments of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("mongowner-graph-file").join(format!(
+            "{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn fragment(
+        struct_name: &str,
+        collection: &str,
+        owners: &[&str],
+        source_file: &Path,
+    ) -> Fragment {
+        Fragment {
+            struct_name: struct_name.to_string(),
+            collection: collection.to_string(),
+            index: vec!["id".to_string()],
+            owned_by: owners
+                .iter()
+                .map(|owner| FragmentEdge {
+                    owner: owner.to_string(),
+                    owner_index: "id".to_string(),
+                    owned_field: format!("{}_id", owner),
+                    kind: EdgeKind::Owned,
+                })
+                .collect(),
+            embedded: Vec::new(),
+            source_file: Some(source_file.to_path_buf()),
+            session: session().to_string(),
+        }
+    }
+
+    fn fragments() -> Vec<Fragment> {
+        let source_file = Path::new(file!()).canonicalize().unwrap();
+        let mut post = fragment("Post", "posts", &["users"], &source_file);
+        post.embedded.push(FragmentEmbedded {
+            owner_collection: "users".to_string(),
+            owner_index: "id".to_string(),
+            array_field: "reactions".to_string(),
+            element_field: Some("user_id".to_string()),
+        });
+        vec![
+            fragment("User", "users", &[], &source_file),
+            post,
+            fragment("Comment", "comments", &["users", "posts"], &source_file),
+        ]
+    }
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    fn graph_files(dir: &Path) -> (String, String, String) {
+        (
+            fs::read_to_string(dir.join("graph.json")).unwrap(),
+            fs::read_to_string(dir.join("index_map.json")).unwrap(),
+            fs::read_to_string(dir.join("embedded.json")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn graph_independent_of_compile_order() {
+        let in_order = test_dir("in-order");
+        for fragment in fragments() {
+            write_fragment_in(&in_order, &fragment).unwrap();
+        }
+        let reversed = test_dir("reversed");
+        for fragment in fragments().iter().rev() {
+            write_fragment_in(&reversed, fragment).unwrap();
+        }
+
+        assert_eq!(graph_files(&in_order), graph_files(&reversed));
+        let (graph, _, embedded) = graph_files(&in_order);
+        assert!(graph.contains("comments") && graph.contains("posts_id"));
+        assert!(embedded.contains("posts") && embedded.contains("reactions"));
+    }
+
+    #[test]
+    fn duplicate_collections_of_a_session() {
+        let dir = test_dir("duplicate");
+        let source_file = Path::new(file!()).canonicalize().unwrap();
+        let mut earlier = fragment("Member", "users", &[], &source_file);
+        earlier.session = "earlier".to_string();
+        write_fragment_in(&dir, &earlier).unwrap();
+        write_fragment_in(&dir, &fragment("User", "users", &[], &source_file)).unwrap();
+
+        let error = write_fragment_in(&dir, &fragment("Admin", "users", &[], &source_file))
+            .unwrap_err()
+            .downcast::<DuplicateCollectionError>()
+            .unwrap();
+        assert_eq!("User", error.struct_name);
+    }
+
+    #[test]
+    fn stale_fragments_removed() {
+        let dir = test_dir("stale");
+        let users_file = dir.join("user.rs");
+        let posts_file = dir.join("post.rs");
+        let comments_file = dir.join("comment.rs");
+        for file in [&users_file, &posts_file, &comments_file] {
+            fs::write(file, "").unwrap();
+            set_modified(file, SystemTime::now() - Duration::from_secs(60));
+        }
+        write_fragment_in(&dir, &fragment("User", "users", &[], &users_file)).unwrap();
+        write_fragment_in(&dir, &fragment("Post", "posts", &["users"], &posts_file)).unwrap();
+        let comment = fragment("Comment", "comments", &["posts"], &comments_file);
+        write_fragment_in(&dir, &comment).unwrap();
+
+        // Comment is removed from a file that is modified, and Post along with its file
+        set_modified(&comments_file, SystemTime::now() + Duration::from_secs(60));
+        fs::remove_file(&posts_file).unwrap();
+        write_fragment_in(&dir, &fragment("User", "users", &[], &users_file)).unwrap();
+
+        let names: Vec<String> = fragment_paths(&dir)
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(vec!["users-User.fragment.json"], names);
+        let (graph, index_map, _) = graph_files(&dir);
+        assert!(!graph.contains("posts") && !graph.contains("comments"));
+        assert_eq!(r#"{"users":["id"]}"#, index_map);
+    }
+}
@@ -1,34 +1,32 @@
 extern crate proc_macro;
+mod graph_file;
+
 use dotenv::dotenv;
-use petgraph::{algo::is_cyclic_directed, graphmap, Directed};
+use graph_file::{
+    session, write_fragment, CycleError, DuplicateCollectionError, EdgeKind, Fragment,
+    FragmentEdge, FragmentEmbedded, OnDelete,
+};
 use proc_macro::TokenStream;
 use proc_macro2::Ident;
-use quote::quote;
-use serde::{Deserialize, Serialize};
-use serde_json;
-use std::{
-    collections::HashMap,
-    env,
-    fs::OpenOptions,
-    io::{Read, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
+use quote::{quote, quote_spanned};
+use syn::ext::IdentExt;
+use syn::meta::ParseNestedMeta;
+use syn::parse::ParseStream;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, token, Attribute, Data, DeriveInput, Expr, Field, Fields, FieldsNamed,
+    LitStr, Meta, Token,
 };
-use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, FieldsNamed, Meta};
 
 // enum to represent all the types of schema annotations
 enum SchemaAnnotations {
     OwnedBy,
+    EmbeddedOwnedBy,
+    References,
     Index,
     CollectionName,
     DataSubject,
-}
-
-/// Represents an edge between two structs.
-/// Ex. for User, Post, we would have owner_index = user_id, owned_field = posted_by
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-struct OwnEdge<'a> {
-    owner_index: &'a str,
-    owned_field: &'a str,
+    SoftDelete,
 }
 
 impl SchemaAnnotations {
@@ -36,127 +34,392 @@ impl SchemaAnnotations {
         match self {
             SchemaAnnotations::Index => "index",
             SchemaAnnotations::OwnedBy => "owned_by",
+            SchemaAnnotations::EmbeddedOwnedBy => "embedded_owned_by",
+            SchemaAnnotations::References => "references",
             SchemaAnnotations::CollectionName => "collection",
             SchemaAnnotations::DataSubject => "data_subject",
+            SchemaAnnotations::SoftDelete => "soft_delete",
         }
     }
 }
 
 /// A custom derive macro meant for data model structs that are connected, in some way,
 /// to a data subject. This produces an implementation of the `Schemable` trait
-/// for this struct.
+/// for this struct, which brings the `delete_cascade`, `delete_by_index` and
+/// `find_by_index` methods of `SchemableExt` along with it (`find_by_index` needs the
+/// struct to implement `Deserialize` as well).
 /// - The #[collection(_)] macro helps identify the name of the collection associated
-/// with the struct in Mongo.
+///   with the struct in Mongo. Each collection is stored by a single struct of the crate.
 /// - The #[owned_by(_)] macro is used to annotate fields containing references to other
-/// models or collections.
-/// - The #[index] macro is used to annotate fields that are primary key of the model
+///   models or collections. The owner collection must belong to a struct deriving `Schema`
+///   (anywhere in the crate or its dependencies), the owner index must be one of that
+///   struct's #[index] fields, and the annotated field must have that field's type, or be
+///   an `Option` or `Vec` of it; otherwise the annotation fails to compile. A `Vec` of
+///   owners is shared by all of them. A struct has at most one #[owned_by(_)] annotation
+///   per owner collection, and if it has several owners, its owned_by fields must be
+///   `Option`s or `Vec`s, since the reference to a deleted owner is cleared from the
+///   documents other owners keep. On a sub-document field,
+///   #[owned_by(_, _, path = "author_id")] refers to the owner reference at that dotted
+///   path inside it, whose type is not checked. Since it can't be checked to be clearable
+///   either, a `path` is only accepted on structs with a single owner.
+/// - The #[embedded_owned_by(_)] macro is used to annotate arrays of owned data embedded in
+///   the document, e.g. #[embedded_owned_by(users, id, path = "user_id")] on a `Vec` of
+///   reactions holding a `user_id` each. Deleting an owner pulls the elements referencing it
+///   out of the array, but keeps the document. Without a `path`, the elements themselves are
+///   the references.
+/// - The #[references(_, _, on_delete = _)] macro is used to annotate fields referencing
+///   documents of another collection that don't own them, e.g.
+///   #[references(questions, id, on_delete = restrict)]. It is checked like #[owned_by(_)].
+///   When a referenced document is deleted, `restrict` (the default) refuses the deletion,
+///   `set_null` clears the reference (so the field must be an `Option` or a `Vec`), and
+///   `cascade` deletes the referencing document along with everything it owns. A struct can
+///   only reference each collection once, whether through #[owned_by(_)] or #[references(_)].
+/// - The #[index] macro is used to annotate fields that are primary key of the model.
+///   Annotating several fields makes a composite index.
+/// - Index and owned_by fields are queried by the names serde stores them under, following
+///   `#[serde(rename = _)]` and `#[serde(rename_all = _)]`. So `#[serde(rename = "_id")]`
+///   indexes on Mongo's `_id`, and owners are referenced by their stored index name, e.g.
+///   #[owned_by(users, _id)].
 /// - The #[data_subject] macro is used to annotate structs that are data subjects
-#[proc_macro_derive(Schema, attributes(owned_by, collection, index, data_subject))]
+/// - The #[soft_delete(retention_days = _)] macro sets how long a soft deletion of a
+///   document of the collection, and of everything it owns, is kept before it may be purged
+///   (`retention_secs` is also accepted). It only sets the retention period: documents are
+///   tombstoned rather than deleted when `DeleteMode::Soft` is passed, annotated or not
+#[proc_macro_derive(
+    Schema,
+    attributes(
+        owned_by,
+        embedded_owned_by,
+        references,
+        collection,
+        index,
+        data_subject,
+        soft_delete
+    )
+)]
 pub fn derive_schema(input: TokenStream) -> TokenStream {
     dotenv().ok();
-    // Parse the collection name from the #[collection(_)] annotation.
     let input = parse_macro_input!(input as DeriveInput);
+    match expand_schema(input) {
+        Ok(gen) => gen.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
 
-    let collection_name =
-        match parse_header_annotation(&input, SchemaAnnotations::CollectionName.as_str()) {
-            Some(name) => name,
-            None => panic!("All schemas must have collection names"),
+// generates the implementation of Schemable for the given struct, or the error to report at
+// the offending tokens
+fn expand_schema(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    // Parse the collection name from the #[collection(_)] annotation.
+    let collection_ident =
+        match find_header_annotation(&input, SchemaAnnotations::CollectionName.as_str()) {
+            Some(attr) => attr.parse_args::<Ident>()?,
+            None => {
+                return Err(syn::Error::new(
+                    input.ident.span(),
+                    "All schemas must have a #[collection(_)] name",
+                ))
+            }
         };
+    let collection_name = collection_ident.to_string();
 
     // TODO: N - this might not actually be needed at all
     // whether or not the given input model is a data_subject
     let is_data_subj =
-        match parse_header_annotation(&input, SchemaAnnotations::DataSubject.as_str()) {
-            Some(_) => true,
-            _ => false,
-        };
+        find_header_annotation(&input, SchemaAnnotations::DataSubject.as_str()).is_some();
+
+    // Retention of tombstones in seconds, if the collection is soft deleted
+    let retention_secs = parse_soft_delete_annotation(&input)?;
 
     // Identify the Rust struct associated with the input string (eg. "User" -> User)
-    let curr_struct_type = generate_struct_type(input.ident.to_string());
+    let curr_struct_type = input.ident.clone();
 
-    let fields = extract_fields_from_schema(input);
+    let fields = extract_fields_from_schema(&input)?;
+    // Fields are queried by the names serde stores them under
+    let rename_all = parse_serde_rename_all(&input)?;
     let owned_by_fields = find_fields_by_annotation(&fields, SchemaAnnotations::OwnedBy.as_str());
 
-    if is_data_subj {
-        if let Some(_) = owned_by_fields {
-            panic!("Data subject cannot have any owned_by field");
+    match (is_data_subj, &owned_by_fields) {
+        (true, Some(fields)) => {
+            return Err(syn::Error::new(
+                annotation_attrs(fields[0], SchemaAnnotations::OwnedBy)
+                    .next()
+                    .unwrap()
+                    .path()
+                    .span(),
+                "Data subject cannot have any owned_by field",
+            ))
         }
-    } else {
-        if let None = owned_by_fields {
-            panic!("Non data subjects MUST have atleast ONE owned_by field");
+        (false, None) => {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "Non data subjects must have at least one #[owned_by(_)] field, or be marked \
+                #[data_subject]",
+            ))
         }
+        _ => {}
     }
 
-    let index_field = match find_field_by_annotation(&fields, SchemaAnnotations::Index.as_str()) {
+    // Several #[index] fields make up a composite index, in declaration order
+    let index_fields = match find_fields_by_annotation(&fields, SchemaAnnotations::Index.as_str()) {
         Some(res) => res,
-        None => panic!("Error finding index_field"),
+        None => {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "All schemas must have an #[index] field",
+            ))
+        }
     };
 
-    let index_field_name = find_field_name(index_field);
-    if let Err(e) = add_index_to_file(&collection_name, &index_field_name) {
-        panic!("Error {:#?} adding index to index file", e);
+    let mut index_idents = Vec::new();
+    let mut index_types = Vec::new();
+    // names of the index fields in Mongo, which serde may rename
+    let mut index_names = Vec::new();
+    for field in &index_fields {
+        index_idents.push(find_field_name(field)?);
+        index_types.push(field.ty.clone());
+        index_names.push(serialized_name(field, rename_all.as_ref())?);
     }
-
-    let index_ident = Ident::new(&index_field_name, proc_macro2::Span::call_site());
-    // let index_type = find_field_type(index_field);
-    let index_type_ident = { index_field.ty.clone() };
+    let index_field_name = index_names[0].clone();
+    let (index_type_ident, index_value, index_pattern) = match index_idents.as_slice() {
+        [index_ident] => (
+            quote! { #(#index_types)* },
+            quote! { self.#index_ident.clone() },
+            quote! { #index_ident },
+        ),
+        _ => (
+            quote! { (#(#index_types),*) },
+            quote! { (#(self.#index_idents.clone()),*) },
+            quote! { (#(#index_idents),*) },
+        ),
+    };
 
     let curr_node_name = curr_struct_type.to_string();
 
-    if let Some(fields) = owned_by_fields {
-        for field in fields {
-            let reference_field = field.ident.as_ref().unwrap().to_string();
-            // TODO: refactor
-            let _ = &field
-                .attrs
-                .get(0)
-                .expect("Error getting field.attrs.get(0)")
-                .parse_nested_meta(|meta| {
-                    let attr_input_stream = meta.input.cursor().token_stream();
-                    let edge_field_opt = attr_input_stream
-                        .into_iter()
-                        .skip(1)
-                        .next()
-                        .and_then(|t| Some(t.to_string()));
-                    if edge_field_opt == None {
-                        return Ok(());
-                    }
-                    let edge_field_name = edge_field_opt.expect("Error getting edge field_name");
-
-                    let owner_coll_name = meta
-                        .path
-                        .get_ident()
-                        .unwrap_or_else(|| panic!("no owner argument in owned_by annotation"))
-                        .to_string();
-
-                    let edge = OwnEdge {
-                        owner_index: &edge_field_name,
-                        owned_field: &reference_field,
-                    };
-
-                    // graph defaults to target dir
-                    let out_dir = env::var("CARGO_MANIFEST_DIR")
-                        .expect("Error reading CARGO_MANIFEST_DIR env variable");
-                    let dir_path = Path::new(&out_dir);
-
-                    let graph_path = dir_path
-                        .join("target")
-                        .join(std::env::var("GRAPH_NAME").unwrap_or("graph.json".to_string()));
-                    println!("DEBUG proc_macro writing to graph at {:#?}", graph_path);
-                    match add_edge_to_file(&collection_name, &owner_coll_name, edge, &graph_path) {
-                        Err(e) => panic!("Could not add edge to file: {:?}", e),
-                        Ok(_) => (),
-                    };
-
-                    Ok(())
-                });
+    // (owner collection, owner index, owned field, kind) of every owned_by and references
+    // annotation
+    let mut owned_by_edges: Vec<(String, String, String, EdgeKind)> = Vec::new();
+    // compile-time checks of each owned_by annotation against the struct of its owner
+    let mut owned_by_checks = Vec::new();
+
+    // A document with several owners outlives the deletion of one of them, whose reference
+    // is then cleared, so it must be a field that can be cleared
+    let owner_count = owned_by_fields
+        .iter()
+        .flatten()
+        .map(|field| annotation_attrs(field, SchemaAnnotations::OwnedBy).count())
+        .sum::<usize>();
+    for field in owned_by_fields.into_iter().flatten() {
+        let field_name = serialized_name(field, rename_all.as_ref())?;
+        let reference_type = &field.ty;
+        for attr in annotation_attrs(field, SchemaAnnotations::OwnedBy) {
+            let (owner_coll, edge_field, path) = parse_owned_by_annotation(attr)?;
+            // The ownership graph holds a single edge from a collection to each owner
+            if owned_by_edges
+                .iter()
+                .any(|(owner, ..)| owner_coll == owner.as_str())
+            {
+                return Err(syn::Error::new(
+                    owner_coll.span(),
+                    format!(
+                        "{} already owns this struct through another #[owned_by] annotation; \
+                        a struct can only reference each owner collection once",
+                        owner_coll
+                    ),
+                ));
+            }
+            // The type of a reference nested in a sub-document isn't known here, so it can't be
+            // checked to be clearable when another owner keeps the document
+            if let Some(path) = path.as_ref().filter(|_| owner_count > 1) {
+                return Err(syn::Error::new(
+                    path.span(),
+                    "a path is only supported on structs with a single owner, since the \
+                    reference it leads to can't be checked to be an `Option` or a `Vec`; use a \
+                    top-level `Option` field for the reference instead",
+                ));
+            }
+            let checked_type = match path {
+                Some(_) => None,
+                None => Some(reference_type),
+            };
+            owned_by_checks.push(owner_check(
+                &owner_coll,
+                &edge_field,
+                checked_type,
+                owner_count > 1,
+            ));
+            let reference_field = match path {
+                Some(path) => format!("{}.{}", field_name, path.value()),
+                None => field_name.clone(),
+            };
+
+            owned_by_edges.push((
+                owner_coll.to_string(),
+                edge_field.to_string(),
+                reference_field,
+                EdgeKind::Owned,
+            ));
+        }
+    }
+
+    // References that don't own the document are edges of their own kind
+    for field in &fields.named {
+        for attr in annotation_attrs(field, SchemaAnnotations::References) {
+            let (referenced_coll, referenced_index, on_delete) = parse_references_annotation(attr)?;
+            if let Some((.., kind)) = owned_by_edges
+                .iter()
+                .find(|(owner, ..)| referenced_coll == owner.as_str())
+            {
+                let annotation = match kind {
+                    EdgeKind::Owned => "an #[owned_by]",
+                    EdgeKind::References(_) => "a #[references]",
+                };
+                return Err(syn::Error::new(
+                    referenced_coll.span(),
+                    format!(
+                        "{} is already referenced through {} annotation; a struct can only \
+                        reference each collection once",
+                        referenced_coll, annotation
+                    ),
+                ));
+            }
+            owned_by_checks.push(owner_check(
+                &referenced_coll,
+                &referenced_index,
+                Some(&field.ty),
+                on_delete == OnDelete::SetNull,
+            ));
+            owned_by_edges.push((
+                referenced_coll.to_string(),
+                referenced_index.to_string(),
+                serialized_name(field, rename_all.as_ref())?,
+                EdgeKind::References(on_delete),
+            ));
+        }
+    }
+
+    // (owner collection, owner index, array field, element field) of every embedded_owned_by
+    // annotation
+    let mut embedded_edges: Vec<(String, String, String, Option<String>)> = Vec::new();
+    for field in &fields.named {
+        for attr in annotation_attrs(field, SchemaAnnotations::EmbeddedOwnedBy) {
+            let (owner_coll, edge_field, path) = parse_owned_by_annotation(attr)?;
+            // Without a path the array holds the references, so it is checked like an
+            // owned_by field
+            let checked_type = match path {
+                Some(_) => None,
+                None => Some(&field.ty),
+            };
+            owned_by_checks.push(owner_check(&owner_coll, &edge_field, checked_type, false));
+            embedded_edges.push((
+                owner_coll.to_string(),
+                edge_field.to_string(),
+                serialized_name(field, rename_all.as_ref())?,
+                path.map(|path| path.value()),
+            ));
         }
     }
 
+    let fragment = Fragment {
+        struct_name: curr_node_name.clone(),
+        collection: collection_name.clone(),
+        index: index_names.clone(),
+        owned_by: owned_by_edges
+            .iter()
+            .map(|(owner, owner_index, owned_field, kind)| FragmentEdge {
+                owner: owner.clone(),
+                owner_index: owner_index.clone(),
+                owned_field: owned_field.clone(),
+                kind: *kind,
+            })
+            .collect(),
+        embedded: embedded_edges
+            .iter()
+            .map(
+                |(owner, owner_index, array_field, element_field)| FragmentEmbedded {
+                    owner_collection: owner.clone(),
+                    owner_index: owner_index.clone(),
+                    array_field: array_field.clone(),
+                    element_field: element_field.clone(),
+                },
+            )
+            .collect(),
+        source_file: proc_macro::Span::call_site()
+            .local_file()
+            .and_then(|path| path.canonicalize().ok()),
+        session: session().to_string(),
+    };
+    // The graph files are only read by tools, since the graph is registered at runtime, so
+    // failing to write them isn't worth failing the build over; a cycle or a collection
+    // stored by two structs is. The rest of the expansion is still generated then, so that
+    // the structs this one owns don't report errors of their own.
+    let graph_error = write_fragment(&fragment).err().and_then(|e| {
+        let error = if e.is::<CycleError>() {
+            syn::Error::new(
+                curr_struct_type.span(),
+                format!("Could not write ownership graph: {}", e),
+            )
+        } else if e.is::<DuplicateCollectionError>() {
+            syn::Error::new(collection_ident.span(), e)
+        } else {
+            return None;
+        };
+        Some(error.to_compile_error())
+    });
+
     // TODO: actually generate the index on the given field and collection
-    // TODO: handle non Uuid field type values
-    // TODO: currently having to hardcode Uuid import and type rather than generically determining
-    // it
+
+    let mut owned_by_entries = Vec::new();
+    let mut references_entries = Vec::new();
+    for (owner_collection, owner_index, owned_field, kind) in &owned_by_edges {
+        match kind {
+            EdgeKind::Owned => owned_by_entries.push(quote! {
+                ::mongowner::registry::OwnedBy {
+                    owner_collection: #owner_collection,
+                    owner_index: #owner_index,
+                    owned_field: #owned_field,
+                }
+            }),
+            EdgeKind::References(on_delete) => {
+                let on_delete = match on_delete {
+                    OnDelete::Restrict => quote! { Restrict },
+                    OnDelete::SetNull => quote! { SetNull },
+                    OnDelete::Cascade => quote! { Cascade },
+                };
+                references_entries.push(quote! {
+                    ::mongowner::registry::References {
+                        referenced_collection: #owner_collection,
+                        referenced_index: #owner_index,
+                        referencing_field: #owned_field,
+                        on_delete: ::mongowner::registry::OnDelete::#on_delete,
+                    }
+                })
+            }
+        }
+    }
+
+    let embedded_entries = embedded_edges.iter().map(
+        |(owner_collection, owner_index, array_field, element_field)| {
+            let element_field = match element_field {
+                Some(element_field) => quote! { ::std::option::Option::Some(#element_field) },
+                None => quote! { ::std::option::Option::None },
+            };
+            quote! {
+                ::mongowner::registry::EmbeddedOwnedBy {
+                    owner_collection: #owner_collection,
+                    owner_index: #owner_index,
+                    array_field: #array_field,
+                    element_field: #element_field,
+                }
+            }
+        },
+    );
+
+    let retention = match retention_secs {
+        Some(secs) => {
+            quote! { ::std::option::Option::Some(::std::time::Duration::from_secs(#secs)) }
+        }
+        None => quote! { ::std::option::Option::None },
+    };
 
     let gen = quote! {
         impl Schemable for #curr_struct_type {
@@ -167,219 +430,413 @@ pub fn derive_schema(input: TokenStream) -> TokenStream {
             fn collection_name() -> &'static str {
                 #collection_name
             }
-            fn cascade_delete(&self) {
-                // delete all documents in ALL fk (owned) schemas where value(fk_field_name) = self._id
-                // delete self from self.collection
-                // TODO: have to have some way of getting and storing the collection name of
-                // both the owner and owned_by schemas
-            }
             fn index_name() -> &'static str {
                 #index_field_name
             }
+            fn index_names() -> &'static [&'static str] {
+                &[#(#index_names),*]
+            }
             fn index_value(&self) -> Self::Value {
-                self.#index_ident.clone()
+                #index_value
             }
-        };
-    };
-    return gen.into();
-}
+            fn index_filter(
+                &self,
+            ) -> ::std::result::Result<::mongowner::mongo::bson::Document, ::mongowner::Error> {
+                let mut filter = ::mongowner::mongo::bson::Document::new();
+                #(
+                    filter.insert(
+                        #index_names,
+                        ::mongowner::registry::stored_bson(&self.#index_idents)?,
+                    );
+                )*
+                ::std::result::Result::Ok(filter)
+            }
+            fn index_filter_for(
+                value: &Self::Value,
+            ) -> ::std::result::Result<::mongowner::mongo::bson::Document, ::mongowner::Error> {
+                let #index_pattern = value;
+                let mut filter = ::mongowner::mongo::bson::Document::new();
+                #(
+                    filter.insert(
+                        #index_names,
+                        ::mongowner::registry::stored_bson(#index_idents)?,
+                    );
+                )*
+                ::std::result::Result::Ok(filter)
+            }
+        }
 
-fn add_index_to_file(
-    collection_name: &str,
-    index_field_name: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let out_dir =
-        env::var("CARGO_MANIFEST_DIR").expect("Error reading CARGO_MANIFEST_DIR env variable");
-    let dir_path = Path::new(&out_dir);
-    let map_path = dir_path
-        .join("target")
-        .join(std::env::var("INDEX_NAME").unwrap_or("index_map.json".to_string()));
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&map_path)?;
-
-    let saved_position = file.seek(SeekFrom::Current(0))?;
-
-    // read file contents to obtain existing map
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let mut map: HashMap<&str, &str> = match serde_json::from_str(&contents) {
-        Ok(g) => g,
-        Err(_) => HashMap::new(),
-    };
+        // Lets the derives of the structs this one owns check their owned_by annotations.
+        impl ::mongowner::registry::OwnerCollection<#curr_struct_type>
+            for ::mongowner::registry::CollectionKey<
+                { ::mongowner::registry::collection_key(#collection_name) },
+            >
+        {
+        }
+        #(
+            impl ::mongowner::registry::OwnerIndex<
+                    { ::mongowner::registry::collection_key(#index_names) },
+                > for #curr_struct_type
+            {
+                type Value = #index_types;
+            }
+        )*
+
+        #(#owned_by_checks)*
+
+        // Registers the ownership details of this struct so that the graph can be built at
+        // runtime without reading any files.
+        ::mongowner::inventory::submit! {
+            ::mongowner::registry::SchemaEntry {
+                struct_name: #curr_node_name,
+                collection_name: #collection_name,
+                index_names: &[#(#index_names),*],
+                owned_by: &[#(#owned_by_entries),*],
+                embedded: &[#(#embedded_entries),*],
+                references: &[#(#references_entries),*],
+                retention: #retention,
+            }
+        }
 
-    match map.insert(collection_name, index_field_name) {
-        Some(s) => println!("Detected collision in index map with value {:#?}", s),
-        None => println!(
-            "New index map entry with {:#?} : {:#?}",
-            collection_name, index_field_name
-        ),
+        #graph_error
     };
+    Ok(gen)
+}
 
-    file.seek(SeekFrom::Start(saved_position))?;
-
-    // write the modified graph back into the file
-    let serialized_map = serde_json::to_string(&map).unwrap();
-    file.write_all(serialized_map.as_bytes())?;
-
-    Ok(())
+// find a header annotation of a struct
+fn find_header_annotation<'a>(input: &'a DeriveInput, annotation: &str) -> Option<&'a Attribute> {
+    input
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident(annotation))
 }
 
-/// Reads the file containing the serialized graph (or creates this file if it doesn't exist),
-/// and writes a modified graph to the file that also contains an edge between `a` and `b`.
-fn add_edge_to_file(
-    owned_node: &str,
-    owner_node: &str,
-    edge: OwnEdge,
-    filepath: &PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // open the file in question and create it if it doesn't exist
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&filepath)?;
-
-    // save starting position of file to seek
-    let saved_position = file.seek(SeekFrom::Current(0))?;
-
-    // read file contents to obtain existing graph
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let mut graph: graphmap::GraphMap<&str, OwnEdge, Directed> =
-        match serde_json::from_str(&contents) {
-            Ok(g) => g,
-            Err(_) => graphmap::GraphMap::new(),
+// parse the retention period of the #[soft_delete(_)] header annotation, in seconds
+fn parse_soft_delete_annotation(input: &DeriveInput) -> syn::Result<Option<u64>> {
+    let attr = match find_header_annotation(input, SchemaAnnotations::SoftDelete.as_str()) {
+        Some(attr) => attr,
+        None => return Ok(None),
+    };
+    let mut retention_secs = None;
+    attr.parse_nested_meta(|meta| {
+        let unit_secs = if meta.path.is_ident("retention_days") {
+            24 * 60 * 60
+        } else if meta.path.is_ident("retention_secs") {
+            1
+        } else {
+            return Err(meta.error("expected retention_days or retention_secs"));
         };
+        let value: syn::LitInt = meta.value()?.parse()?;
+        retention_secs = Some(value.base10_parse::<u64>()? * unit_secs);
+        Ok(())
+    })?;
+    match retention_secs {
+        Some(secs) => Ok(Some(secs)),
+        None => Err(syn::Error::new(
+            attr.span(),
+            "soft_delete annotation must have a retention_days or retention_secs",
+        )),
+    }
+}
 
-    // add the edge to the graph
-    let node_a = if graph.contains_node(owned_node) {
-        owned_node
-    } else {
-        graph.add_node(owned_node)
+// the name of a field in Mongo: its Rust name, unless serde renames it when serializing,
+// either by itself or through the struct's rename_all rule
+fn serialized_name(field: &Field, rename_all: Option<&RenameRule>) -> syn::Result<String> {
+    let ident = find_field_name(field)?.unraw().to_string();
+    let mut name = match rename_all {
+        Some(rule) => rule.apply(&ident),
+        None => ident,
     };
-    let node_b = if graph.contains_node(owner_node) {
-        owner_node
-    } else {
-        graph.add_node(owner_node)
-    };
-    graph.add_edge(node_a, node_b, edge);
-
-    // Check that adding this edge doesn't introduce a cycle
-    if is_cyclic_directed(&graph) {
-        println!("\nDEBUG: cycle detected\n");
-        return Err(format!(
-            "Adding edge ({}, {}) created cycle in ownership graph.",
-            node_a, node_b
-        )
-        .into());
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                if let Some(rename) = parse_serialize_name(&meta)? {
+                    name = rename.value();
+                }
+            } else {
+                skip_meta_value(&meta)?;
+            }
+            Ok(())
+        })?;
     }
+    Ok(name)
+}
 
-    // restore the saved position
-    file.seek(SeekFrom::Start(saved_position))?;
-
-    // write the modified graph back into the file
-    let serialized_graph = serde_json::to_string(&graph).unwrap();
-    file.write_all(serialized_graph.as_bytes())?;
+// parse the #[serde(rename_all = _)] rule of a struct, if it has one
+fn parse_serde_rename_all(input: &DeriveInput) -> syn::Result<Option<RenameRule>> {
+    let mut rename_all = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename_all") {
+                if let Some(rule) = parse_serialize_name(&meta)? {
+                    rename_all = Some(RenameRule::parse(&rule)?);
+                }
+            } else {
+                skip_meta_value(&meta)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(rename_all)
+}
 
-    Ok(())
+// the name used when serializing out of serde's `name = ".."` or
+// `name(serialize = "..", deserialize = "..")`
+fn parse_serialize_name(meta: &ParseNestedMeta) -> syn::Result<Option<LitStr>> {
+    if meta.input.peek(Token![=]) {
+        return Ok(Some(meta.value()?.parse()?));
+    }
+    let mut name = None;
+    meta.parse_nested_meta(|inner| {
+        let value = inner.value()?.parse::<LitStr>()?;
+        if inner.path.is_ident("serialize") {
+            name = Some(value);
+        }
+        Ok(())
+    })?;
+    Ok(name)
 }
 
-// generates a Ident that can be used with the # operator in quote to reference actual Rust defined
-// types
-fn generate_struct_type(type_str: String) -> Ident {
-    syn::Ident::new(&type_str, proc_macro2::Span::call_site())
+// the case conventions of serde's rename_all, as applied to snake_case field names
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
 }
 
-// parse the header annotations of a struct
-fn parse_header_annotation(input: &DeriveInput, annotation: &str) -> Option<String> {
-    let mut a = None;
-    for attr in &input.attrs {
-        if let Meta::Path(mp) = &attr.meta {
-            for seg in &mp.segments {
-                if seg.ident.to_string() == annotation {
-                    a = Some(seg.ident.to_string());
-                    break;
-                }
-            }
+impl RenameRule {
+    fn parse(rule: &LitStr) -> syn::Result<Self> {
+        match rule.value().as_str() {
+            "lowercase" => Ok(RenameRule::Lower),
+            "UPPERCASE" => Ok(RenameRule::Upper),
+            "PascalCase" => Ok(RenameRule::Pascal),
+            "camelCase" => Ok(RenameRule::Camel),
+            "snake_case" => Ok(RenameRule::Snake),
+            "SCREAMING_SNAKE_CASE" => Ok(RenameRule::ScreamingSnake),
+            "kebab-case" => Ok(RenameRule::Kebab),
+            "SCREAMING-KEBAB-CASE" => Ok(RenameRule::ScreamingKebab),
+            _ => Err(syn::Error::new(
+                rule.span(),
+                "unknown serde rename_all rule",
+            )),
         }
-        if let Meta::List(ml) = &attr.meta {
-            for seg in &ml.path.segments {
-                if seg.ident.to_string() == annotation {
-                    a = Some(ml.tokens.to_string());
-                    break;
+    }
+
+    fn apply(&self, field: &str) -> String {
+        match self {
+            RenameRule::Lower | RenameRule::Snake => field.to_string(),
+            RenameRule::Upper | RenameRule::ScreamingSnake => field.to_ascii_uppercase(),
+            RenameRule::Pascal => field
+                .split('_')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                        None => String::new(),
+                    }
+                })
+                .collect(),
+            RenameRule::Camel => {
+                let pascal = RenameRule::Pascal.apply(field);
+                let mut chars = pascal.chars();
+                match chars.next() {
+                    Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+                    None => String::new(),
                 }
             }
+            RenameRule::Kebab => field.replace('_', "-"),
+            RenameRule::ScreamingKebab => field.replace('_', "-").to_ascii_uppercase(),
         }
     }
-    a
 }
 
-// fn find_field_type(field: &Field) -> String {
-//     // TODO: a few cases to handle -
-//     // type name is just one path length e.g. Uuid
-//     // path length > 1 e.g. mongowner::mongo::bson::uuid::Uuid
-//     match &field.ty {
-//         syn::Type::Path(p) => p
-//             .path
-//             .segments
-//             .iter()
-//             .map(|s| s.ident.to_string())
-//             .collect::<Vec<String>>()
-//             .join("::"),
-//         // syn::Type::Path(p) => match p.path.segments.first() {
-//         //     Some(ps) => ps.ident.to_string(),
-//         //     None => panic!("No type found for field"),
-//         // },
-//         _ => panic!("error parsing field!"),
-//     }
-// }
-//
-// extract the fields from a given schema
-fn extract_fields_from_schema(input: DeriveInput) -> FieldsNamed {
-    if let Data::Struct(data) = input.data {
-        if let Fields::Named(fields) = data.fields {
-            fields
-        } else {
-            panic!("Could not read any fields from struct")
-        }
-    } else {
-        panic!("Could not read any fields from struct")
+// skip over the value of a nested meta item that isn't looked at, e.g. `= "..."` or `(...)`
+fn skip_meta_value(meta: &ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(token::Paren) {
+        meta.input.parse::<proc_macro2::Group>()?;
     }
+    Ok(())
 }
 
-// generic field parser to find annotated fields
-fn find_field_by_annotation<'a>(fields: &'a FieldsNamed, annotation: &str) -> Option<&'a Field> {
-    for field in fields.named.iter() {
-        if field.attrs.len() > 0 {
-            for attr in &field.attrs {
-                if let Meta::Path(ml) = &attr.meta {
-                    for seg in &ml.segments {
-                        if seg.ident.to_string() == annotation.to_string() {
-                            return Some(field);
-                        }
-                    }
-                }
-                if let Meta::List(ml) = &attr.meta {
-                    for seg in &ml.path.segments {
-                        if seg.ident.to_string() == annotation.to_string() {
-                            return Some(field);
-                        }
-                    }
-                }
+// the annotations of a field of the given kind
+fn annotation_attrs(
+    field: &Field,
+    annotation: SchemaAnnotations,
+) -> impl Iterator<Item = &Attribute> {
+    field
+        .attrs
+        .iter()
+        .filter(move |attr| attr.path().is_ident(annotation.as_str()))
+}
+
+// the compile-time check of an owned_by (or embedded_owned_by, or references) annotation
+// against the struct of its owner, which also checks `reference_type` against the type of
+// the owner index if it is given, and that it can be cleared if it must be `nullable`. The
+// owner's struct is found through the key of its collection, so the check compiles wherever
+// the owner is declared, and errors point at the annotation.
+fn owner_check(
+    owner_coll: &Ident,
+    owner_index: &Ident,
+    reference_type: Option<&syn::Type>,
+    nullable: bool,
+) -> proc_macro2::TokenStream {
+    let owner_coll_name = owner_coll.to_string();
+    let owner_index_name = owner_index.to_string();
+    let owner_of = quote_spanned! {owner_coll.span()=>
+        ::mongowner::registry::owner_of::<
+            { ::mongowner::registry::collection_key(#owner_coll_name) },
+            { ::mongowner::registry::collection_key(#owner_index_name) },
+            _,
+        >()
+    };
+    let check = match nullable {
+        true => quote! { check_nullable_reference },
+        false => quote! { check_reference },
+    };
+    match reference_type {
+        Some(reference_type) => quote_spanned! {reference_type.span()=>
+            const _: fn(&#reference_type) = |reference| {
+                ::mongowner::registry::#check(#owner_of, reference);
+            };
+        },
+        None => quote! {
+            const _: fn() = || {
+                let _ = #owner_of;
+            };
+        },
+    }
+}
+
+// parse the owner collection and owner index of an #[owned_by(_)] (or
+// #[embedded_owned_by(_)]) annotation, and the `path = ".."` of a reference nested in the
+// annotated sub-document field, or in the elements of the annotated array
+fn parse_owned_by_annotation(attr: &Attribute) -> syn::Result<(Ident, Ident, Option<LitStr>)> {
+    attr.parse_args_with(|input: ParseStream| {
+        let owner_coll: Ident = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let owner_index: Ident = input.parse()?;
+        let mut path = None;
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let key: Ident = input.parse()?;
+            if key != "path" {
+                return Err(syn::Error::new(key.span(), "expected `path`"));
+            }
+            input.parse::<Token![=]>()?;
+            let value: LitStr = input.parse()?;
+            let valid = value
+                .value()
+                .split('.')
+                .all(|segment| !segment.is_empty() && !segment.starts_with('$'));
+            if !valid {
+                return Err(syn::Error::new(
+                    value.span(),
+                    "path must be a dotted path of field names, e.g. \"meta.author_id\"",
+                ));
+            }
+            path = Some(value);
+            input.parse::<Option<Token![,]>>()?;
+        }
+        Ok((owner_coll, owner_index, path))
+    })
+    .map_err(|e| {
+        let name = attr
+            .path()
+            .get_ident()
+            .map(Ident::to_string)
+            .unwrap_or_default();
+        syn::Error::new(
+            e.span(),
+            format!(
+                "{}; {} annotations take the form #[{}(owner_collection, owner_index)] or \
+                #[{}(owner_collection, owner_index, path = \"nested.field\")]",
+                e, name, name, name
+            ),
+        )
+    })
+}
+
+// parse the referenced collection and index of a #[references(_)] annotation, and its
+// `on_delete` action, which defaults to restrict
+fn parse_references_annotation(attr: &Attribute) -> syn::Result<(Ident, Ident, OnDelete)> {
+    attr.parse_args_with(|input: ParseStream| {
+        let referenced_coll: Ident = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let referenced_index: Ident = input.parse()?;
+        let mut on_delete = OnDelete::Restrict;
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let key: Ident = input.parse()?;
+            if key != "on_delete" {
+                return Err(syn::Error::new(key.span(), "expected `on_delete`"));
             }
+            input.parse::<Token![=]>()?;
+            let action: Ident = input.parse()?;
+            on_delete = match action.to_string().as_str() {
+                "restrict" => OnDelete::Restrict,
+                "set_null" => OnDelete::SetNull,
+                "cascade" => OnDelete::Cascade,
+                _ => {
+                    return Err(syn::Error::new(
+                        action.span(),
+                        "expected `restrict`, `set_null` or `cascade`",
+                    ))
+                }
+            };
+            input.parse::<Option<Token![,]>>()?;
         }
+        Ok((referenced_coll, referenced_index, on_delete))
+    })
+    .map_err(|e| {
+        syn::Error::new(
+            e.span(),
+            format!(
+                "{}; references annotations take the form #[references(collection, index)] or \
+                #[references(collection, index, on_delete = restrict|set_null|cascade)]",
+                e
+            ),
+        )
+    })
+}
+
+// extract the fields from a given schema
+fn extract_fields_from_schema(input: &DeriveInput) -> syn::Result<FieldsNamed> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields.clone()),
+            fields => Err(syn::Error::new(
+                fields.span(),
+                "Schema can only be derived for structs with named fields",
+            )),
+        },
+        _ => Err(syn::Error::new(
+            input.ident.span(),
+            "Schema can only be derived for structs",
+        )),
     }
-    None
 }
 
-// given a syn::Field object, it returns the string name of the annotated field
-fn find_field_name(field: &Field) -> String {
+// given a syn::Field object, it returns the name of the annotated field
+fn find_field_name(field: &Field) -> syn::Result<Ident> {
     match &field.ident {
-        Some(i) => i.to_string(),
-        None => panic!("Could not find annotated field"),
+        Some(i) => Ok(i.clone()),
+        None => Err(syn::Error::new(
+            field.span(),
+            "Could not find the name of annotated field",
+        )),
     }
 }
 
@@ -390,26 +847,24 @@ fn find_fields_by_annotation<'a>(
 ) -> Option<Vec<&'a Field>> {
     let mut found: Vec<&Field> = Vec::new();
     for field in fields.named.iter() {
-        if field.attrs.len() > 0 {
-            for attr in &field.attrs {
-                if let Meta::Path(ml) = &attr.meta {
-                    for seg in &ml.segments {
-                        if seg.ident.to_string() == annotation.to_string() {
-                            found.push(field);
-                        }
+        for attr in &field.attrs {
+            if let Meta::Path(ml) = &attr.meta {
+                for seg in &ml.segments {
+                    if seg.ident == annotation {
+                        found.push(field);
                     }
                 }
-                if let Meta::List(ml) = &attr.meta {
-                    for seg in &ml.path.segments {
-                        if seg.ident.to_string() == annotation.to_string() {
-                            found.push(field);
-                        }
+            }
+            if let Meta::List(ml) = &attr.meta {
+                for seg in &ml.path.segments {
+                    if seg.ident == annotation {
+                        found.push(field);
                     }
                 }
             }
         }
     }
-    if found.len() > 0 {
+    if !found.is_empty() {
         return Some(found);
     }
     None
@@ -0,0 +1,43 @@
+use crate::delete::DeleteMode;
+
+use mongodb::bson::{Bson, DateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Proof that a data subject was deleted, as returned by `safe_delete`. When
+/// `DeleteOptions::audit_collection` is set, the same receipt is stored in that collection
+/// along with the deletion (inside its transaction, if it runs in one).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeletionReceipt {
+    /// The collection of the data subject.
+    pub collection: String,
+    /// The index value of the data subject, or a document of its index fields for a
+    /// composite index. A bulk deletion such as `safe_delete_by_ids` lists those of all
+    /// its subjects in an array.
+    pub subject: Bson,
+    /// When the deletion ran; for a soft deletion, the time of its tombstones.
+    pub deleted_at: DateTime,
+    /// Whether the documents were removed or tombstoned.
+    pub mode: DeleteMode,
+    /// Number of documents deleted (or tombstoned), by the name of their collection.
+    /// Collections where nothing was deleted are left out.
+    pub deleted_counts: BTreeMap<String, u64>,
+    /// The `graph_version` of the ownership graph the deletion followed.
+    pub graph_version: String,
+    /// Whether `verify_deleted` found nothing left behind once the deletion was done.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+impl DeletionReceipt {
+    /// Total number of documents deleted (or tombstoned) across all collections.
+    pub fn total_count(&self) -> u64 {
+        self.deleted_counts.values().sum()
+    }
+
+    /// Whether the data subject itself (any of them, for a bulk deletion) was deleted (or
+    /// tombstoned), rather than only what was left of it.
+    pub fn subject_deleted(&self) -> bool {
+        self.deleted_counts.contains_key(&self.collection)
+    }
+}
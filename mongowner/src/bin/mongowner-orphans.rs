@@ -0,0 +1,138 @@
+//! Scans a database for orphaned documents, i.e. documents whose owners no longer exist,
+//! and optionally deletes them along with everything they own.
+//!
+//! The ownership graph is read from the graph.json that the `Schema` derive writes for the
+//! application's crate, e.g. target/mongowner/<crate>/graph.json. Deleting also needs the
+//! embedded.json written next to it, so that the embedded entries of deleted orphans are
+//! pulled as well; `--delete` refuses to run without it. Orphans that can't be deleted are
+//! reported, and make the command fail once the others are deleted.
+
+use mongodb::Client;
+use mongowner::orphans::{delete_orphans_with_graph, find_orphans_with_graph, OrphanReport};
+use mongowner::util::{load_embedded_file, load_graph_file};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: mongowner-orphans --db <name> --graph <graph.json> \
+[--uri <mongodb uri>] [--delete [--embedded <embedded.json>]]";
+
+struct Args {
+    uri: String,
+    db: String,
+    graph: PathBuf,
+    /// Defaults to the embedded.json next to `graph`.
+    embedded: Option<PathBuf>,
+    delete: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut uri = "mongodb://localhost:27017".to_string();
+    let mut db = None;
+    let mut graph = None;
+    let mut embedded = None;
+    let mut delete = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--uri" => uri = args.next().ok_or("--uri needs a value")?,
+            "--db" => db = Some(args.next().ok_or("--db needs a value")?),
+            "--graph" => graph = Some(PathBuf::from(args.next().ok_or("--graph needs a value")?)),
+            "--embedded" => {
+                embedded = Some(PathBuf::from(
+                    args.next().ok_or("--embedded needs a value")?,
+                ))
+            }
+            "--delete" => delete = true,
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok(Args {
+        uri,
+        db: db.ok_or("--db is required")?,
+        graph: graph.ok_or("--graph is required")?,
+        embedded,
+        delete,
+    })
+}
+
+fn print_report(report: &OrphanReport) {
+    for (collection_name, ids) in &report.orphans {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        println!(
+            "{}: {} orphans: {}",
+            collection_name,
+            ids.len(),
+            ids.join(", ")
+        );
+    }
+    println!("{} orphans in total", report.total_count());
+    for (collection_name, count) in &report.deleted_counts {
+        println!("deleted {} documents from {}", count, collection_name);
+    }
+    for failure in &report.failures {
+        println!(
+            "could not delete {} {}: {}",
+            failure.collection, failure.id, failure.error
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    // Deleting orphans without their embedded entries would leave those behind
+    let mut embedded_contents = String::new();
+    let embedded = match args.delete {
+        true => {
+            let path = args
+                .embedded
+                .clone()
+                .unwrap_or_else(|| args.graph.with_file_name("embedded.json"));
+            match load_embedded_file(&path, &mut embedded_contents) {
+                Ok(embedded) => embedded,
+                Err(e) => {
+                    eprintln!(
+                        "error: {}\n--delete needs the embedded.json written next to graph.json, \
+                        or one passed with --embedded",
+                        e
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        false => Vec::new(),
+    };
+
+    let mut contents = String::new();
+    let result = async {
+        let graph = load_graph_file(&args.graph, &mut contents)?;
+        let client = Client::with_uri_str(&args.uri).await?;
+        let db = client.database(&args.db);
+        match args.delete {
+            true => delete_orphans_with_graph(&graph, &embedded, &db).await,
+            false => find_orphans_with_graph(&graph, &db).await,
+        }
+    }
+    .await;
+
+    match result {
+        Ok(report) => {
+            print_report(&report);
+            match report.failures.is_empty() {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,751 @@
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::progress::DeleteEvent;
+use crate::registry::{EmbeddedOwnedBy, OnDelete};
+use crate::retention::{retention_of, DELETED_AT, PURGE_AFTER};
+use crate::util::*;
+
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use petgraph::{graphmap::GraphMap, Directed, Direction};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Instant;
+use tracing::{debug, debug_span, Instrument};
+
+/// The outcome of a deletion, resolved before any document is touched: which documents are
+/// deleted, and which are kept but lose their references to deleted owners.
+///
+/// Ownership is shared: a document with several owner references is only deleted once every
+/// one of them points at a deleted owner. References to owners that no longer exist do not
+/// keep a document alive. A document with an `on_delete = cascade` reference to a deleted
+/// document is deleted whatever its owners, and documents with other `#[references]` to
+/// deleted documents either have them cleared or prevent the deletion.
+pub(crate) struct Cascade<'g> {
+    /// Every collection the cascade can reach, owners before the collections they own, with
+    /// their distance from the root collection in the ownership graph.
+    pub(crate) order: Vec<(&'g str, usize)>,
+    /// The root documents of the cascade, if any exist.
+    pub(crate) roots: Vec<Document>,
+    /// Documents to delete, by collection. The root's entry also holds the index fields of
+    /// the roots that don't exist, so that what they owned is still cleaned up.
+    pub(crate) deleted: HashMap<&'g str, Vec<Document>>,
+    /// Documents that outlive the cascade, by collection, with the update that removes their
+    /// references to deleted owners, or to deleted documents they reference with
+    /// `on_delete = set_null`. These may be in collections the cascade doesn't reach.
+    pub(crate) unlinked: HashMap<&'g str, Vec<(Document, Document)>>,
+    /// Embedded entries referencing deleted owners, by the collection holding them, with the
+    /// owner index values they are pulled by.
+    pub(crate) embedded: Vec<(&'g str, EmbeddedOwnedBy<'g>, Vec<Bson>)>,
+}
+
+impl<'g> Cascade<'g> {
+    /// Works out what deleting the documents of `root_coll` matching any of `root_filters`
+    /// (index filters, one per root) entails, all in one cascade, following `graph` and the
+    /// `#[embedded_owned_by]` annotations of `embedded`, using only reads from `executor`.
+    /// The deleted documents also hold the index fields `index_map` lists for their
+    /// collection, if any, for reporting them. Fails with `Error::Restricted` if a document that outlives the cascade references a
+    /// deleted one with `on_delete = restrict`, and with `Error::Cancelled` if the deletion
+    /// is cancelled meanwhile.
+    pub(crate) async fn resolve(
+        root_coll: &'g str,
+        root_filters: Vec<Document>,
+        graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+        embedded: &[(&'g str, EmbeddedOwnedBy<'g>)],
+        index_map: &HashMap<&str, &[&str]>,
+        executor: &mut Executor<'_>,
+    ) -> Result<Cascade<'g>, Error> {
+        let order = reachable_in_order(root_coll, graph)?;
+        let roots = find_roots(root_coll, &root_filters, executor).await?;
+        // The index fields are the same for every filter, so the roots are keyed by them once
+        let fields: Vec<String> = root_filters
+            .first()
+            .map(|filter| filter.keys().cloned().collect())
+            .unwrap_or_default();
+        let found: HashSet<String> = roots.iter().map(|root| index_key(root, &fields)).collect();
+        let mut deleted_roots = roots.clone();
+        deleted_roots.extend(
+            root_filters
+                .into_iter()
+                .filter(|filter| !found.contains(&index_key(filter, &fields))),
+        );
+        let mut cascade = Cascade {
+            order: order.clone(),
+            roots,
+            deleted: HashMap::from([(root_coll, deleted_roots)]),
+            unlinked: HashMap::new(),
+            embedded: Vec::new(),
+        };
+
+        for (collection_name, depth) in order.into_iter().skip(1) {
+            let span = debug_span!("resolve_collection", collection = collection_name, depth);
+            let started = Instant::now();
+            cascade
+                .resolve_collection(collection_name, graph, embedded, index_map, executor)
+                .instrument(span.clone())
+                .await?;
+            span.in_scope(|| {
+                debug!(
+                    deleted = cascade.deleted.get(collection_name).map_or(0, Vec::len),
+                    unlinked = cascade.unlinked.get(collection_name).map_or(0, Vec::len),
+                    elapsed_ms = elapsed_ms(started),
+                    "resolved collection"
+                )
+            });
+            executor.check_cancelled(&BTreeMap::new())?;
+        }
+
+        // The owner index of each embedded entry was fetched along with every deleted owner
+        for &(collection_name, embedded) in embedded {
+            let values: Vec<Bson> = cascade
+                .deleted
+                .get(embedded.owner_collection)
+                .into_iter()
+                .flatten()
+                .filter_map(|owner| owner.get(embedded.owner_index).cloned())
+                .collect();
+            if !values.is_empty() {
+                cascade.embedded.push((collection_name, embedded, values));
+            }
+        }
+
+        // References that don't cascade only matter once every deleted document is known
+        for (collection_name, referenced_coll, edge) in graph.all_edges() {
+            if let EdgeKind::References(on_delete @ (OnDelete::Restrict | OnDelete::SetNull)) =
+                edge.kind
+            {
+                cascade
+                    .resolve_references(collection_name, referenced_coll, edge, on_delete, executor)
+                    .await?;
+            }
+        }
+
+        executor.emit(|| {
+            let mut counts = BTreeMap::new();
+            add_count(&mut counts, root_coll, cascade.roots.len() as u64);
+            for (collection_name, deleted) in &cascade.deleted {
+                if *collection_name != root_coll {
+                    add_count(&mut counts, collection_name, deleted.len() as u64);
+                }
+            }
+            DeleteEvent::Resolved { counts }
+        });
+        Ok(cascade)
+    }
+
+    /// Decides the fate of every document of `collection_name` that references a deleted
+    /// owner. All of its owner collections must already have been resolved.
+    async fn resolve_collection(
+        &mut self,
+        collection_name: &'g str,
+        graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+        embedded: &[(&'g str, EmbeddedOwnedBy<'g>)],
+        index_map: &HashMap<&str, &[&str]>,
+        executor: &mut Executor<'_>,
+    ) -> Result<(), Error> {
+        let owner_edges: Vec<(&'g str, OwnEdge<'g>)> = graph
+            .edges_directed(collection_name, Direction::Outgoing)
+            .filter(|(_, _, edge)| edge.kind.cascades())
+            .map(|(_, owner_coll, edge)| (owner_coll, *edge))
+            .collect();
+
+        // Only the fields the cascade looks at are fetched: the references to owners, the
+        // fields the collection's own documents are referenced by, whether by other documents
+        // or by embedded entries, and its index.
+        let mut projection = doc! { "_id": 1 };
+        for (_, edge) in &owner_edges {
+            projection.insert(edge.owned_field, 1);
+        }
+        for (_, _, edge) in graph.edges_directed(collection_name, Direction::Incoming) {
+            projection.insert(edge.owner_index, 1);
+        }
+        for (_, embedded) in embedded
+            .iter()
+            .filter(|(_, embedded)| embedded.owner_collection == collection_name)
+        {
+            projection.insert(embedded.owner_index, 1);
+        }
+        for index_name in index_map.get(collection_name).copied().unwrap_or_default() {
+            projection.insert(*index_name, 1);
+        }
+
+        // Values of the owner index of each owner collection that are being deleted, and the
+        // documents that reference any of them
+        let mut deleted_refs: Vec<HashSet<String>> = Vec::new();
+        let mut candidates = Vec::new();
+        let mut candidate_ids = HashSet::new();
+        for (owner_coll, edge) in &owner_edges {
+            let mut values: Vec<Bson> = Vec::new();
+            for owner in self.deleted.get(owner_coll).into_iter().flatten() {
+                match owner.get(edge.owner_index) {
+                    Some(value) => values.push(value.clone()),
+                    None => {
+                        return Err(Error::MissingOwnerField {
+                            collection: owner_coll.to_string(),
+                            field: edge.owner_index.to_string(),
+                        })
+                    }
+                }
+            }
+            deleted_refs.push(values.iter().map(bson_key).collect());
+            let found = executor
+                .find_in(
+                    collection_name,
+                    edge.owned_field,
+                    values,
+                    Document::new(),
+                    Some(projection.clone()),
+                )
+                .await?;
+            // A document referencing deleted owners through several edges is found once
+            // per edge
+            candidates.extend(found.into_iter().filter(|candidate| {
+                candidate_ids.insert(bson_key(candidate.get("_id").unwrap_or(&Bson::Null)))
+            }));
+        }
+        if candidates.is_empty() {
+            return Ok(());
+        }
+
+        // The owners each candidate references, per owner edge
+        let mut candidate_refs: Vec<Vec<Vec<Bson>>> = Vec::new();
+        for candidate in &candidates {
+            let mut refs = Vec::new();
+            for (_, edge) in &owner_edges {
+                refs.push(references(candidate, collection_name, edge.owned_field)?);
+            }
+            candidate_refs.push(refs);
+        }
+
+        // Find out which of the remaining references still point at an existing owner. The
+        // targets of cascading references don't keep a document alive, and neither do
+        // tombstoned owners, which are only waiting to be purged.
+        let mut live_refs: Vec<HashSet<String>> = Vec::new();
+        for (i, (owner_coll, edge)) in owner_edges.iter().enumerate() {
+            if edge.kind != EdgeKind::Owned {
+                live_refs.push(HashSet::new());
+                continue;
+            }
+            let unresolved: Vec<Bson> = candidate_refs
+                .iter()
+                .flat_map(|refs| refs[i].iter())
+                .filter(|r| !deleted_refs[i].contains(&bson_key(r)))
+                .cloned()
+                .collect();
+            let owners = executor
+                .find_in(
+                    owner_coll,
+                    edge.owner_index,
+                    unresolved,
+                    doc! { DELETED_AT: { "$exists": false } },
+                    Some(doc! { edge.owner_index: 1 }),
+                )
+                .await?;
+            live_refs.push(
+                owners
+                    .iter()
+                    .filter_map(|owner| owner.get(edge.owner_index))
+                    .map(bson_key)
+                    .collect(),
+            );
+        }
+
+        for (candidate, refs) in candidates.into_iter().zip(candidate_refs) {
+            let mut keeps_owner = false;
+            let mut cascaded = false;
+            let mut unset = Document::new();
+            let mut pull = Document::new();
+            for (i, ((_, edge), refs)) in owner_edges.iter().zip(refs).enumerate() {
+                keeps_owner |= refs.iter().any(|r| live_refs[i].contains(&bson_key(r)));
+                let gone: Vec<Bson> = refs
+                    .into_iter()
+                    .filter(|r| deleted_refs[i].contains(&bson_key(r)))
+                    .collect();
+                if gone.is_empty() {
+                    continue;
+                }
+                cascaded |= edge.kind != EdgeKind::Owned;
+                match get_path(&candidate, collection_name, edge.owned_field)? {
+                    Some(Bson::Array(_)) => {
+                        pull.insert(edge.owned_field, doc! { "$in": gone });
+                    }
+                    _ => {
+                        unset.insert(edge.owned_field, "");
+                    }
+                }
+            }
+
+            if keeps_owner && !cascaded {
+                let mut update = Document::new();
+                if !unset.is_empty() {
+                    update.insert("$unset", unset);
+                }
+                if !pull.is_empty() {
+                    update.insert("$pull", pull);
+                }
+                self.unlinked
+                    .entry(collection_name)
+                    .or_default()
+                    .push((candidate, update));
+            } else {
+                self.deleted
+                    .entry(collection_name)
+                    .or_default()
+                    .push(candidate);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles the documents of `collection_name` that reference deleted documents of
+    /// `referenced_coll` through `edge`, which doesn't cascade: with `OnDelete::Restrict`,
+    /// any such document that outlives the cascade fails the deletion, and with
+    /// `OnDelete::SetNull` its reference is cleared.
+    async fn resolve_references(
+        &mut self,
+        collection_name: &'g str,
+        referenced_coll: &'g str,
+        edge: &OwnEdge<'g>,
+        on_delete: OnDelete,
+        executor: &mut Executor<'_>,
+    ) -> Result<(), Error> {
+        let values: Vec<Bson> = self
+            .deleted
+            .get(referenced_coll)
+            .into_iter()
+            .flatten()
+            .filter_map(|referenced| referenced.get(edge.owner_index).cloned())
+            .collect();
+        if values.is_empty() {
+            return Ok(());
+        }
+        let gone: HashSet<String> = values.iter().map(bson_key).collect();
+        let deleted: HashSet<String> = self
+            .deleted_ids(collection_name)
+            .iter()
+            .map(bson_key)
+            .collect();
+
+        let referencing = executor
+            .find_in(
+                collection_name,
+                edge.owned_field,
+                values,
+                Document::new(),
+                Some(doc! { "_id": 1, edge.owned_field: 1 }),
+            )
+            .await?;
+        for document in referencing {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+            if deleted.contains(&bson_key(&id)) {
+                continue;
+            }
+            if on_delete == OnDelete::Restrict {
+                return Err(Error::Restricted {
+                    collection: referenced_coll.to_string(),
+                    referencing_collection: collection_name.to_string(),
+                    field: edge.owned_field.to_string(),
+                });
+            }
+            let update = match get_path(&document, collection_name, edge.owned_field)? {
+                Some(Bson::Array(refs)) => {
+                    let cleared: Vec<Bson> = refs
+                        .iter()
+                        .filter(|r| gone.contains(&bson_key(r)))
+                        .cloned()
+                        .collect();
+                    doc! { "$pull": { edge.owned_field: { "$in": cleared } } }
+                }
+                _ => doc! { "$set": { edge.owned_field: Bson::Null } },
+            };
+            self.unlinked
+                .entry(collection_name)
+                .or_default()
+                .push((document, update));
+        }
+
+        Ok(())
+    }
+
+    /// Carries out the cascade with the queries of `executor`. Owned collections are handled
+    /// before their owners and the root documents go last, so a cascade that fails midway
+    /// leaves owners behind rather than orphans. Each collection is handled in bulk: one
+    /// query per batch of deleted documents, and per batch of unlinked documents that need
+    /// the same update. Embedded entries of deleted owners, and references to deleted
+    /// documents from collections the cascade doesn't reach, are cleared first. Returns the
+    /// number of documents deleted from each collection, or fails with `Error::Cancelled`
+    /// once the deletion is cancelled, after the batch it was deleting.
+    pub(crate) async fn execute(
+        &self,
+        executor: &mut Executor<'_>,
+    ) -> Result<BTreeMap<String, u64>, Error> {
+        let mut counts = BTreeMap::new();
+        for (collection_name, embedded, values) in &self.embedded {
+            let started = Instant::now();
+            let modified = executor
+                .pull_in(
+                    collection_name,
+                    embedded.array_field,
+                    embedded.element_field,
+                    values.clone(),
+                )
+                .await?;
+            debug!(
+                collection = collection_name,
+                array_field = embedded.array_field,
+                modified,
+                elapsed_ms = elapsed_ms(started),
+                "pulled embedded entries"
+            );
+            executor.check_cancelled(&counts)?;
+        }
+        for &collection_name in self.unlinked.keys() {
+            if !self.order[1..]
+                .iter()
+                .any(|&(name, _)| name == collection_name)
+            {
+                self.unlink(collection_name, executor).await?;
+                executor.check_cancelled(&counts)?;
+            }
+        }
+
+        let steps: Vec<(&str, usize)> = self.order.iter().skip(1).rev().copied().collect();
+        for (i, &(collection_name, depth)) in steps.iter().enumerate() {
+            executor.emit(|| DeleteEvent::CollectionEntered {
+                collection: collection_name.to_string(),
+                depth,
+            });
+            let span = debug_span!("delete_collection", collection = collection_name, depth);
+            let started = Instant::now();
+            self.unlink(collection_name, executor)
+                .instrument(span.clone())
+                .await?;
+            let deleted = executor
+                .delete_in(collection_name, self.deleted_ids(collection_name))
+                .instrument(span.clone())
+                .await?;
+            span.in_scope(|| {
+                debug!(
+                    deleted,
+                    unlinked = self.unlinked.get(collection_name).map_or(0, Vec::len),
+                    elapsed_ms = elapsed_ms(started),
+                    "deleted documents"
+                )
+            });
+            add_count(&mut counts, collection_name, deleted);
+            if executor.is_cancelled() {
+                break;
+            }
+            emit_level_completed(executor, &steps[i + 1..], depth);
+        }
+
+        executor.check_cancelled(&counts)?;
+        let (root_coll, _) = self.order[0];
+        executor.emit(|| DeleteEvent::CollectionEntered {
+            collection: root_coll.to_string(),
+            depth: 0,
+        });
+        let started = Instant::now();
+        let deleted = executor.delete_in(root_coll, self.root_ids()).await?;
+        debug!(
+            collection = root_coll,
+            depth = 0,
+            deleted,
+            elapsed_ms = elapsed_ms(started),
+            "deleted root documents"
+        );
+        executor.emit(|| DeleteEvent::LevelCompleted { depth: 0 });
+        add_count(&mut counts, root_coll, deleted);
+
+        Ok(counts)
+    }
+
+    /// Applies the updates of the documents of `collection_name` that outlive the cascade,
+    /// with one query per batch of documents that need the same update.
+    async fn unlink(
+        &self,
+        collection_name: &str,
+        executor: &mut Executor<'_>,
+    ) -> Result<(), Error> {
+        let mut updates: Vec<(&Document, Vec<Bson>)> = Vec::new();
+        for (document, update) in self.unlinked.get(collection_name).into_iter().flatten() {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+            match updates.iter_mut().find(|(other, _)| *other == update) {
+                Some((_, ids)) => ids.push(id),
+                None => updates.push((update, vec![id])),
+            }
+        }
+        for (update, ids) in updates {
+            executor
+                .update_in(collection_name, ids, Document::new(), update.clone())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Soft-deletes the cascade with the queries of `executor`: every document it would
+    /// delete is marked with a `DELETED_AT` tombstone of `deleted_at` instead, and with a
+    /// `PURGE_AFTER` time once the retention period of the root collection is over, leaving
+    /// it to `purge_expired` to remove then. Documents that are already tombstoned keep
+    /// their original timestamps, and shared documents and embedded entries keep their
+    /// references so that the deletion can be undone by removing the tombstones. Returns the
+    /// number of documents tombstoned in each collection, or fails with `Error::Cancelled`
+    /// once the deletion is cancelled, counting those tombstoned.
+    pub(crate) async fn tombstone(
+        &self,
+        deleted_at: DateTime,
+        executor: &mut Executor<'_>,
+    ) -> Result<BTreeMap<String, u64>, Error> {
+        let mut counts = BTreeMap::new();
+        let (root_coll, _) = self.order[0];
+        let purge_after = DateTime::from_millis(
+            deleted_at
+                .timestamp_millis()
+                .saturating_add(retention_of(root_coll).as_millis() as i64),
+        );
+        let update = doc! { "$set": { DELETED_AT: deleted_at, PURGE_AFTER: purge_after } };
+        let steps: Vec<(&str, usize)> = self.order.iter().skip(1).rev().copied().collect();
+        for (i, &(collection_name, depth)) in steps.iter().enumerate() {
+            executor.emit(|| DeleteEvent::CollectionEntered {
+                collection: collection_name.to_string(),
+                depth,
+            });
+            let span = debug_span!("tombstone_collection", collection = collection_name, depth);
+            let started = Instant::now();
+            let tombstoned = executor
+                .update_in(
+                    collection_name,
+                    self.deleted_ids(collection_name),
+                    doc! { DELETED_AT: { "$exists": false } },
+                    update.clone(),
+                )
+                .instrument(span.clone())
+                .await?;
+            span.in_scope(|| {
+                debug!(
+                    tombstoned,
+                    elapsed_ms = elapsed_ms(started),
+                    "tombstoned documents"
+                )
+            });
+            executor.emit(|| DeleteEvent::DocumentsDeleted {
+                collection: collection_name.to_string(),
+                count: tombstoned,
+            });
+            add_count(&mut counts, collection_name, tombstoned);
+            if executor.is_cancelled() {
+                break;
+            }
+            emit_level_completed(executor, &steps[i + 1..], depth);
+        }
+
+        executor.check_cancelled(&counts)?;
+        executor.emit(|| DeleteEvent::CollectionEntered {
+            collection: root_coll.to_string(),
+            depth: 0,
+        });
+        let tombstoned = executor
+            .update_in(
+                root_coll,
+                self.root_ids(),
+                doc! { DELETED_AT: { "$exists": false } },
+                update,
+            )
+            .await?;
+        executor.emit(|| DeleteEvent::DocumentsDeleted {
+            collection: root_coll.to_string(),
+            count: tombstoned,
+        });
+        executor.emit(|| DeleteEvent::LevelCompleted { depth: 0 });
+        add_count(&mut counts, root_coll, tombstoned);
+
+        Ok(counts)
+    }
+
+    /// The `_id`s of the root documents.
+    fn root_ids(&self) -> Vec<Bson> {
+        self.roots
+            .iter()
+            .filter_map(|root| root.get("_id").cloned())
+            .collect()
+    }
+
+    /// The `_id`s of the documents of `collection_name` that are deleted.
+    fn deleted_ids(&self, collection_name: &str) -> Vec<Bson> {
+        self.deleted
+            .get(collection_name)
+            .into_iter()
+            .flatten()
+            .filter_map(|document| document.get("_id").cloned())
+            .collect()
+    }
+}
+
+/// Adds `count` documents of `collection_name` to `counts`, leaving out collections where
+/// nothing happened.
+fn add_count(counts: &mut BTreeMap<String, u64>, collection_name: &str, count: u64) {
+    if count > 0 {
+        *counts.entry(collection_name.to_string()).or_default() += count;
+    }
+}
+
+/// Reports that every collection `depth` edges away from the root is done, unless one of the
+/// collections of the `remaining` steps is as far away.
+fn emit_level_completed(executor: &Executor<'_>, remaining: &[(&str, usize)], depth: usize) {
+    if remaining.iter().all(|&(_, other)| other != depth) {
+        executor.emit(|| DeleteEvent::LevelCompleted { depth });
+    }
+}
+
+/// Returns `root_coll` and every collection that it directly or indirectly owns (or that
+/// references it with `on_delete = cascade`), sorted so that owners come before the
+/// collections they own, along with their distance from `root_coll`.
+pub(crate) fn reachable_in_order<'g>(
+    root_coll: &'g str,
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+) -> Result<Vec<(&'g str, usize)>, Error> {
+    if !graph.contains_node(root_coll) {
+        return Ok(vec![(root_coll, 0)]);
+    }
+
+    let mut depths = HashMap::from([(root_coll, 0)]);
+    let mut queue = VecDeque::from([root_coll]);
+    while let Some(collection_name) = queue.pop_front() {
+        let depth = depths[collection_name];
+        for (child_coll, _, edge) in graph.edges_directed(collection_name, Direction::Incoming) {
+            if edge.kind.cascades() && !depths.contains_key(child_coll) {
+                depths.insert(child_coll, depth + 1);
+                queue.push_back(child_coll);
+            }
+        }
+    }
+    // Edges point from owned collections to their owners, so a topological sort puts owned
+    // collections first.
+    let sorted = cascade_order(graph)?;
+    Ok(sorted
+        .into_iter()
+        .rev()
+        .filter_map(|collection_name| {
+            depths
+                .get(collection_name)
+                .map(|&depth| (collection_name, depth))
+        })
+        .collect())
+}
+
+/// Values referenced by `field` of `document` in `collection_name`: the elements of an owner
+/// array, or the single owner id of a scalar field. `field` may be a dotted path into
+/// sub-documents.
+fn references(document: &Document, collection_name: &str, field: &str) -> Result<Vec<Bson>, Error> {
+    match get_path(document, collection_name, field)? {
+        None | Some(Bson::Null) => Ok(Vec::new()),
+        Some(Bson::Array(values)) => Ok(values
+            .iter()
+            .filter(|value| **value != Bson::Null)
+            .cloned()
+            .collect()),
+        Some(value @ Bson::Document(_)) => Err(Error::TypeMismatch {
+            collection: collection_name.to_string(),
+            field: field.to_string(),
+            found: format!("{:?}", value.element_type()),
+        }),
+        Some(value) => Ok(vec![value.clone()]),
+    }
+}
+
+/// The value at the dotted `path` of `document` in `collection_name`, if there is one. Fails
+/// if the path runs through something other than sub-documents, such as an array of them,
+/// since which of their references belongs to the owner could not be told apart.
+pub(crate) fn get_path<'d>(
+    document: &'d Document,
+    collection_name: &str,
+    path: &str,
+) -> Result<Option<&'d Bson>, Error> {
+    let mut current = document;
+    let mut segments = path.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        let value = match current.get(segment) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if segments.peek().is_none() {
+            return Ok(Some(value));
+        }
+        match value {
+            Bson::Document(inner) => current = inner,
+            Bson::Null => return Ok(None),
+            other => {
+                return Err(Error::TypeMismatch {
+                    collection: collection_name.to_string(),
+                    field: path.to_string(),
+                    found: format!("{:?}", other.element_type()),
+                })
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the documents of `root_coll` matching any of `root_filters`, one query per batch of
+/// filters: a single `$in` over the index values when the index is a single field, and an
+/// `$or` of the filters otherwise.
+pub(crate) async fn find_roots(
+    root_coll: &str,
+    root_filters: &[Document],
+    executor: &mut Executor<'_>,
+) -> Result<Vec<Document>, Error> {
+    let single_field = root_filters
+        .first()
+        .filter(|first| first.len() == 1)
+        .and_then(|first| first.keys().next())
+        .filter(|&field| {
+            root_filters
+                .iter()
+                .all(|filter| filter.len() == 1 && filter.contains_key(field))
+        });
+    if let Some(field) = single_field {
+        let values = root_filters
+            .iter()
+            .filter_map(|filter| filter.get(field).cloned())
+            .collect();
+        return Ok(executor
+            .find_in(root_coll, field, values, Document::new(), None)
+            .await?);
+    }
+
+    let mut roots = Vec::new();
+    let mut ids = HashSet::new();
+    for batch in root_filters.chunks(executor.batch_size.max(1)) {
+        let filter = match batch {
+            [filter] => filter.clone(),
+            _ => doc! { "$or": batch.to_vec() },
+        };
+        // A root matching filters of several batches is found once per batch
+        let found = executor.find(root_coll, filter, None).await?;
+        roots.extend(
+            found
+                .into_iter()
+                .filter(|root| ids.insert(bson_key(root.get("_id").unwrap_or(&Bson::Null)))),
+        );
+    }
+    Ok(roots)
+}
+
+/// A hashable key for the values of `document` at the index `fields`.
+fn index_key(document: &Document, fields: &[String]) -> String {
+    fields
+        .iter()
+        .map(|field| document.get(field).map(bson_key).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\u{0}")
+}
+
+/// A hashable key for `value` under which numbers compare equal regardless of their BSON
+/// type, matching how Mongo compares them in queries.
+pub(crate) fn bson_key(value: &Bson) -> String {
+    match value {
+        Bson::Int32(n) => n.to_string(),
+        Bson::Int64(n) => n.to_string(),
+        Bson::Double(n) if n.fract() == 0.0 => format!("{}", *n as i64),
+        other => other.to_string(),
+    }
+}
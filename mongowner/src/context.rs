@@ -0,0 +1,246 @@
+use crate::audit::DeletionReceipt;
+use crate::delete::{self, DeleteOptions, Schemable};
+use crate::error::Error;
+use crate::export::{self, SubjectExport};
+use crate::jobs::{self, DeletionJob, WorkerOptions};
+use crate::orphans::{self, OrphanReport};
+use crate::plan::{self, DeletePlan};
+use crate::progress::{Cancellation, Progress, ProgressEvents};
+use crate::retention;
+use crate::util::*;
+use crate::verify::{self, Verification};
+
+use mongodb::bson::{oid::ObjectId, Document};
+use mongodb::{Client, Database};
+use petgraph::{graphmap::GraphMap, Directed};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::sync::Arc;
+
+/// A database together with the ownership graph of this binary, loaded once when the
+/// context is built rather than on every deletion. Cloning is cheap and clones share the
+/// graph, so a single context can be handed to every worker of a web server.
+///
+/// Its methods mirror the free functions of mongowner, which load the graph on each call.
+#[derive(Clone, Debug)]
+pub struct Mongowner {
+    client: Client,
+    db: Database,
+    graph: Arc<GraphMap<&'static str, OwnEdge<'static>, Directed>>,
+}
+
+impl Mongowner {
+    /// Loads the ownership graph and builds a context for the database `db_name` of
+    /// `client`.
+    pub fn new(client: Client, db_name: &str) -> Result<Self, Error> {
+        let graph = load_graph()?;
+        Ok(Mongowner {
+            db: client.database(db_name),
+            client,
+            graph: Arc::new(graph),
+        })
+    }
+
+    /// The client the context was built with.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The database the context operates on.
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// The ownership graph loaded when the context was built.
+    pub fn graph(&self) -> &GraphMap<&'static str, OwnEdge<'static>, Directed> {
+        &self.graph
+    }
+
+    /// See `delete::safe_delete`.
+    pub async fn safe_delete<T: Schemable>(&self, to_delete: T) -> Result<DeletionReceipt, Error> {
+        self.safe_delete_with_options(to_delete, DeleteOptions::default())
+            .await
+    }
+
+    /// See `delete::safe_delete_with_options`.
+    pub async fn safe_delete_with_options<T: Schemable>(
+        &self,
+        to_delete: T,
+        options: DeleteOptions,
+    ) -> Result<DeletionReceipt, Error> {
+        delete::delete_with_graph(to_delete, &self.graph, &self.db, &options, None).await
+    }
+
+    /// See `progress::safe_delete_with_progress`.
+    pub fn safe_delete_with_progress<'a, T: Schemable + 'a>(
+        &'a self,
+        to_delete: T,
+        options: DeleteOptions,
+        cancellation: Cancellation,
+    ) -> (
+        ProgressEvents,
+        impl Future<Output = Result<DeletionReceipt, Error>> + 'a,
+    ) {
+        let (progress, events) = Progress::new(cancellation);
+        let deletion = async move {
+            delete::delete_with_graph(to_delete, &self.graph, &self.db, &options, Some(progress))
+                .await
+        };
+        (events, deletion)
+    }
+
+    /// See `delete::safe_delete_transaction`.
+    pub async fn safe_delete_transaction<T: Schemable>(
+        &self,
+        to_delete: T,
+    ) -> Result<DeletionReceipt, Error> {
+        self.safe_delete_transaction_with_options(to_delete, DeleteOptions::default())
+            .await
+    }
+
+    /// See `delete::safe_delete_transaction_with_options`.
+    pub async fn safe_delete_transaction_with_options<T: Schemable>(
+        &self,
+        to_delete: T,
+        options: DeleteOptions,
+    ) -> Result<DeletionReceipt, Error> {
+        delete::delete_transaction_with_graph(
+            to_delete,
+            &self.graph,
+            &self.client,
+            &self.db,
+            &options,
+        )
+        .await
+    }
+
+    /// Finds the document of type `T` whose index is `value`.
+    pub async fn find_by_index<T>(&self, value: T::Value) -> Result<Option<T>, Error>
+    where
+        T: Schemable + DeserializeOwned + Unpin + Send + Sync,
+    {
+        let filter = T::index_filter_for(&value)?;
+        let collection = self.db.collection::<T>(T::collection_name());
+        Ok(collection.find_one(filter, None).await?)
+    }
+
+    /// Safely deletes the document of type `T` whose index is `value` with
+    /// `safe_delete_by_id`, returning `None`, and deleting nothing, if there is no such
+    /// document.
+    pub async fn delete_by_index<T: Schemable>(
+        &self,
+        value: T::Value,
+    ) -> Result<Option<DeletionReceipt>, Error> {
+        delete::delete_by_index_with_graph::<T>(value, &self.graph, &self.db).await
+    }
+
+    /// See `delete::safe_delete_by_id`.
+    pub async fn safe_delete_by_id<T: Schemable>(
+        &self,
+        value: T::Value,
+    ) -> Result<DeletionReceipt, Error> {
+        self.safe_delete_by_id_with_options::<T>(value, DeleteOptions::default())
+            .await
+    }
+
+    /// See `delete::safe_delete_by_id_with_options`.
+    pub async fn safe_delete_by_id_with_options<T: Schemable>(
+        &self,
+        value: T::Value,
+        options: DeleteOptions,
+    ) -> Result<DeletionReceipt, Error> {
+        delete::delete_by_id_with_graph::<T>(value, &self.graph, &self.db, &options).await
+    }
+
+    /// See `delete::safe_delete_by_ids`.
+    pub async fn safe_delete_by_ids<T: Schemable>(
+        &self,
+        values: impl IntoIterator<Item = T::Value>,
+    ) -> Result<DeletionReceipt, Error> {
+        self.safe_delete_by_ids_with_options::<T>(values, DeleteOptions::default())
+            .await
+    }
+
+    /// See `delete::safe_delete_by_ids_with_options`.
+    pub async fn safe_delete_by_ids_with_options<T: Schemable>(
+        &self,
+        values: impl IntoIterator<Item = T::Value>,
+        options: DeleteOptions,
+    ) -> Result<DeletionReceipt, Error> {
+        let values = values.into_iter().collect();
+        delete::delete_by_ids_with_graph::<T>(values, &self.graph, &self.db, &options).await
+    }
+
+    /// See `delete::safe_delete_matching`.
+    pub async fn safe_delete_matching<T: Schemable>(
+        &self,
+        filter: Document,
+    ) -> Result<DeletionReceipt, Error> {
+        self.safe_delete_matching_with_options::<T>(filter, DeleteOptions::default())
+            .await
+    }
+
+    /// See `delete::safe_delete_matching_with_options`.
+    pub async fn safe_delete_matching_with_options<T: Schemable>(
+        &self,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<DeletionReceipt, Error> {
+        delete::delete_matching_with_graph::<T>(filter, &self.graph, &self.db, &options).await
+    }
+
+    /// See `plan::plan_delete`.
+    pub async fn plan_delete<T: Schemable>(&self, to_plan: &T) -> Result<DeletePlan, Error> {
+        plan::plan_with_graph(to_plan, &self.graph, &self.db).await
+    }
+
+    /// See `export::export_subject`.
+    pub async fn export_subject<T: Schemable>(&self, subject: &T) -> Result<SubjectExport, Error> {
+        export::export_with_graph(subject, &self.graph, &self.db).await
+    }
+
+    /// See `verify::verify_deleted`.
+    pub async fn verify_deleted<T: Schemable>(
+        &self,
+        index_value: T::Value,
+    ) -> Result<Verification, Error> {
+        verify::verify_with_graph::<T>(index_value, &self.graph, &self.db).await
+    }
+
+    /// See `retention::purge_expired`.
+    pub async fn purge_expired(&self) -> Result<u64, Error> {
+        retention::purge_with_graph(&self.graph, &self.db).await
+    }
+
+    /// See `jobs::enqueue_delete`.
+    pub async fn enqueue_delete<T: Schemable>(
+        &self,
+        to_delete: &T,
+        options: DeleteOptions,
+    ) -> Result<ObjectId, Error> {
+        jobs::enqueue_delete(to_delete, &self.db, options).await
+    }
+
+    /// See `jobs::run_next_job`.
+    pub async fn run_next_job(&self, worker: &WorkerOptions) -> Result<Option<DeletionJob>, Error> {
+        jobs::run_next_with_graph(&self.graph, &self.db, worker).await
+    }
+
+    /// See `jobs::run_pending_jobs`.
+    pub async fn run_pending_jobs(
+        &self,
+        worker: &WorkerOptions,
+    ) -> Result<Vec<DeletionJob>, Error> {
+        jobs::run_pending_with_graph(&self.graph, &self.db, worker).await
+    }
+
+    /// See `orphans::find_orphans`.
+    pub async fn find_orphans(&self) -> Result<OrphanReport, Error> {
+        orphans::find_orphans_with_graph(&self.graph, &self.db).await
+    }
+
+    /// See `orphans::delete_orphans`.
+    pub async fn delete_orphans(&self) -> Result<OrphanReport, Error> {
+        orphans::delete_orphans_with_graph(&self.graph, &load_embedded(), &self.db).await
+    }
+}
@@ -1,12 +1,19 @@
+use crate::audit::DeletionReceipt;
+use crate::cascade::Cascade;
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::progress::Progress;
 use crate::util::*;
+use crate::verify::verify_subject;
 
-use async_recursion::async_recursion;
-use futures::future::try_join_all;
-use futures::stream::TryStreamExt;
-use mongodb::bson::Document;
-use mongodb::{bson::doc, Database};
-use petgraph::{graphmap::GraphMap, Directed, Direction};
+use mongodb::bson::{Bson, DateTime, Document};
+use mongodb::{bson::doc, Client, Database};
+use petgraph::{graphmap::GraphMap, Directed};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt::Debug;
+use std::future::Future;
+use std::time::Instant;
+use tracing::{info, instrument};
 
 /// The `Schemable` trait provides the details associated with a data model struct,
 /// necessary to safely delete it and all the data an instance of this model owns.
@@ -14,98 +21,532 @@ pub trait Schemable {
     type Value: Debug;
     fn struct_name() -> &'static str;
     fn collection_name() -> &'static str;
-    fn cascade_delete(&self);
+    /// The name of the index field in Mongo; the first one of a composite index.
     fn index_name() -> &'static str;
+    /// The names of the index fields in Mongo, in declaration order; several for a
+    /// composite index.
+    fn index_names() -> &'static [&'static str];
+    /// The value of the index; a tuple of the index fields for a composite index.
     fn index_value(&self) -> Self::Value;
+    /// A filter matching this document by its index fields, encoded as they are stored.
+    fn index_filter(&self) -> Result<Document, Error>;
+    /// A filter matching the document whose index is `value`, like `index_filter`.
+    fn index_filter_for(value: &Self::Value) -> Result<Document, Error>;
+}
+
+/// Shorthands for finding and deleting the documents of a `Schemable` struct, available on
+/// every struct deriving `Schema`. Only `find_by_index` needs the struct to implement
+/// `Deserialize`.
+pub trait SchemableExt: Schemable + Sized {
+    /// Deletes this document and everything it owns with `safe_delete`.
+    fn delete_cascade(
+        self,
+        db: &Database,
+    ) -> impl Future<Output = Result<DeletionReceipt, Error>> + Send
+    where
+        Self: Send,
+    {
+        safe_delete(self, db)
+    }
+
+    /// Deletes the document whose index is `value` and everything it owns with
+    /// `safe_delete_by_id`, without loading it first. Returns `None`, deleting nothing, if
+    /// there is no such document.
+    fn delete_by_index(
+        value: Self::Value,
+        db: &Database,
+    ) -> impl Future<Output = Result<Option<DeletionReceipt>, Error>> + Send
+    where
+        Self::Value: Send,
+    {
+        async move {
+            let graph = load_graph()?;
+            delete_by_index_with_graph::<Self>(value, &graph, db).await
+        }
+    }
+
+    /// Finds the document whose index is `value`.
+    fn find_by_index(
+        value: Self::Value,
+        db: &Database,
+    ) -> impl Future<Output = Result<Option<Self>, Error>> + Send
+    where
+        Self: DeserializeOwned + Unpin + Send + Sync,
+        Self::Value: Send,
+    {
+        async move {
+            let filter = Self::index_filter_for(&value)?;
+            let collection = db.collection::<Self>(Self::collection_name());
+            Ok(collection.find_one(filter, None).await?)
+        }
+    }
+}
+
+impl<T: Schemable> SchemableExt for T {}
+
+/// How `safe_delete_with_options` disposes of the documents a deletion reaches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    /// Physically remove the documents, as `safe_delete` does.
+    #[default]
+    Hard,
+    /// Mark every document of the owned subtree with a `DELETED_AT` tombstone, to be
+    /// removed by `purge_expired` once the retention period of the subject's collection is
+    /// over. Every collection is tombstoned, whether or not it has a `#[soft_delete]`
+    /// annotation: the annotation only sets the retention period.
+    Soft,
+}
+
+/// The number of values `safe_delete` lists in a single `$in` query unless told otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Options of `safe_delete_with_options`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeleteOptions {
+    /// How the documents the deletion reaches are disposed of.
+    pub mode: DeleteMode,
+    /// The most values listed in a single `$in` query. The documents of each collection are
+    /// found and deleted with one query per batch of their owners' (or their own) ids.
+    pub batch_size: usize,
+    /// The collection to store the `DeletionReceipt` of the deletion in, if any.
+    pub audit_collection: Option<String>,
+    /// Whether to check that a hard deletion left nothing behind, as `verify_deleted` does,
+    /// failing with `Error::Unverified` if it did. A transactional deletion is checked before
+    /// it is committed, so it is rolled back instead. Soft deletions aren't checked, since
+    /// they keep their documents.
+    pub verify: bool,
+}
+
+impl Default for DeleteOptions {
+    fn default() -> Self {
+        DeleteOptions {
+            mode: DeleteMode::Hard,
+            batch_size: DEFAULT_BATCH_SIZE,
+            audit_collection: None,
+            verify: false,
+        }
+    }
 }
 
 /// Safe deletion for an object that implements the `Schemable` trait, where "safety"
 /// is defined as the property that deleting a `Schemable` deletes all of the data it
 /// exclusively owns, i.e. leaves no orphaned data.
+/// Data that is shared with other owners (i.e. has several `owned_by` references) is only
+/// deleted along with its last owner; until then, just its reference to `to_delete` (or to
+/// anything else this deletes) is removed.
+/// Returns a receipt of what was deleted.
 pub async fn safe_delete<T: Schemable>(
     to_delete: T,
     db: &Database,
-) -> Result<(), Box<dyn std::error::Error>>
-where
-    mongodb::bson::Bson: From<<T as Schemable>::Value>,
-{
-    let mut contents = String::new();
-    let graph = load_graph(&mut contents)?;
-
-    // Look up to_delete.collection_name in the graph to get a starting point
-    // User
-    let curr_coll_name = T::collection_name();
-
-    // Get the immediate neighboring edges of to_delete to structs that to_delete owns
-    let edges_to_children = graph.edges_directed(&curr_coll_name, Direction::Incoming);
-
-    for (child_coll, _, edge) in edges_to_children {
-        let collection = db.collection::<Document>(child_coll);
-        let found_cursor = collection
-            .find(doc! { edge.owned_field: to_delete.index_value() }, None)
-            .await?;
-
-        // Call safe_delete_document on each of the found results. Join all of the resulting promises.
-        let doc_vec: Vec<Document> = found_cursor.try_collect().await?;
-        let delete_promises: Vec<_> = doc_vec
-            .iter()
-            .map(|doc| safe_delete_children(doc, child_coll, &graph, &db))
-            .collect();
-        try_join_all(delete_promises).await?;
-
-        // Call delete_many on all found elements of our collection
-        collection
-            .delete_many(doc! { edge.owned_field: to_delete.index_value() }, None)
-            .await?;
+) -> Result<DeletionReceipt, Error> {
+    safe_delete_with_options(to_delete, db, DeleteOptions::default()).await
+}
+
+/// Variant of `safe_delete` that disposes of `to_delete` and everything it owns according
+/// to `options`. A soft deletion resolves the same documents as a hard one, but only
+/// tombstones them; shared documents are left untouched until the tombstones are purged.
+pub async fn safe_delete_with_options<T: Schemable>(
+    to_delete: T,
+    db: &Database,
+    options: DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let graph = load_graph()?;
+    delete_with_graph(to_delete, &graph, db, &options, None).await
+}
+
+/// Variant of `safe_delete` taking the index value of the document of type `T` to delete,
+/// so that the document needn't be loaded, or even be deserializable, first. If there is no
+/// such document, whatever still references it is deleted all the same, and the receipt's
+/// `subject_deleted` is false.
+pub async fn safe_delete_by_id<T: Schemable>(
+    value: T::Value,
+    db: &Database,
+) -> Result<DeletionReceipt, Error> {
+    safe_delete_by_id_with_options::<T>(value, db, DeleteOptions::default()).await
+}
+
+/// Variant of `safe_delete_by_id` that disposes of the document and everything it owns
+/// according to `options`.
+pub async fn safe_delete_by_id_with_options<T: Schemable>(
+    value: T::Value,
+    db: &Database,
+    options: DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let graph = load_graph()?;
+    delete_by_id_with_graph::<T>(value, &graph, db, &options).await
+}
+
+/// Bulk variant of `safe_delete_by_id`, deleting the documents of type `T` whose index is
+/// one of `values`, and everything they own, in a single cascade. Documents shared by
+/// several of them are deleted as any document whose owners are all deleted. The receipt's
+/// `subject` is the array of the deleted index values.
+pub async fn safe_delete_by_ids<T: Schemable>(
+    values: impl IntoIterator<Item = T::Value>,
+    db: &Database,
+) -> Result<DeletionReceipt, Error> {
+    safe_delete_by_ids_with_options::<T>(values, db, DeleteOptions::default()).await
+}
+
+/// Variant of `safe_delete_by_ids` that disposes of the documents and everything they own
+/// according to `options`.
+pub async fn safe_delete_by_ids_with_options<T: Schemable>(
+    values: impl IntoIterator<Item = T::Value>,
+    db: &Database,
+    options: DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let graph = load_graph()?;
+    delete_by_ids_with_graph::<T>(values.into_iter().collect(), &graph, db, &options).await
+}
+
+/// Bulk variant of `safe_delete_by_id`, deleting every document of type `T` that matches
+/// `filter`, of which only the index fields are read, in a single cascade like
+/// `safe_delete_by_ids`. Fails with `Error::MissingIndexField` without deleting anything if
+/// a matching document lacks one of its index fields.
+pub async fn safe_delete_matching<T: Schemable>(
+    filter: Document,
+    db: &Database,
+) -> Result<DeletionReceipt, Error> {
+    safe_delete_matching_with_options::<T>(filter, db, DeleteOptions::default()).await
+}
+
+/// Variant of `safe_delete_matching` that disposes of the documents and everything they own
+/// according to `options`.
+pub async fn safe_delete_matching_with_options<T: Schemable>(
+    filter: Document,
+    db: &Database,
+    options: DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let graph = load_graph()?;
+    delete_matching_with_graph::<T>(filter, &graph, db, &options).await
+}
+
+/// `safe_delete_by_id_with_options` following `graph`.
+pub(crate) async fn delete_by_id_with_graph<T: Schemable>(
+    value: T::Value,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+    options: &DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let index_filter = T::index_filter_for(&value)?;
+    let subject = subject_of(&index_filter);
+    delete_roots_with_graph(
+        T::collection_name(),
+        vec![index_filter],
+        subject,
+        graph,
+        db,
+        options,
+        None,
+    )
+    .await
+}
+
+/// `SchemableExt::delete_by_index` following `graph`: `delete_by_id_with_graph` if the
+/// document exists, and nothing otherwise.
+pub(crate) async fn delete_by_index_with_graph<T: Schemable>(
+    value: T::Value,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+) -> Result<Option<DeletionReceipt>, Error> {
+    let index_filter = T::index_filter_for(&value)?;
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+    let found = executor
+        .find(T::collection_name(), index_filter, Some(doc! { "_id": 1 }))
+        .await?;
+    if found.is_empty() {
+        return Ok(None);
+    }
+    let options = DeleteOptions::default();
+    let receipt = delete_by_id_with_graph::<T>(value, graph, db, &options).await?;
+    Ok(Some(receipt))
+}
+
+/// `safe_delete_by_ids_with_options` following `graph`.
+pub(crate) async fn delete_by_ids_with_graph<T: Schemable>(
+    values: Vec<T::Value>,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+    options: &DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let mut filters = Vec::new();
+    for value in &values {
+        filters.push(T::index_filter_for(value)?);
+    }
+    let subject = Bson::Array(filters.iter().map(subject_of).collect());
+    delete_roots_with_graph(
+        T::collection_name(),
+        filters,
+        subject,
+        graph,
+        db,
+        options,
+        None,
+    )
+    .await
+}
+
+/// `safe_delete_matching_with_options` following `graph`.
+pub(crate) async fn delete_matching_with_graph<T: Schemable>(
+    filter: Document,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+    options: &DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let mut projection = doc! { "_id": 0 };
+    for index_name in T::index_names() {
+        projection.insert(*index_name, 1);
     }
-    // Delete to_delete
-    db.collection::<T>(curr_coll_name)
-        .delete_one(doc! { T::index_name() : to_delete.index_value() }, None)
+    let mut executor = Executor::new(db, options.batch_size);
+    let matching = executor
+        .find(T::collection_name(), filter, Some(projection))
         .await?;
 
-    Ok(())
-}
-
-/// Helper function for safe_delete that operates on documents instead of schemables.
-/// This is the function that recurs internally when a user calls safe_delete. This does
-/// not delete the inputted document itself, but it does delete everything that document
-/// directly or indirectly owns.
-/// Note: the ?Send annotation prevents communication between threads; this is a quick
-/// fix to the dyn Error type being un-Send-able. May revisit.
-#[async_recursion(?Send)]
-async fn safe_delete_children<'a>(
-    to_delete: &Document,
-    collection_name: &str,
-    graph: &GraphMap<&str, OwnEdge<'a>, Directed>,
-    db: &Database,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Get edges incoming towards to_delete's collection
-    let edges_to_children = graph.edges_directed(&collection_name, Direction::Incoming);
-    let mut owner_id = None;
-
-    // Recursively call safe_delete_document on every document that the current
-    // document owns
-    for (child_coll, _, edge) in edges_to_children {
-        let collection = db.collection::<Document>(child_coll);
-        // Get the owner's id if we haven't already
-        if owner_id.is_none() {
-            owner_id = to_delete.get(edge.owner_index).unwrap().into();
+    let mut filters = Vec::new();
+    for document in matching {
+        let mut index_filter = Document::new();
+        for index_name in T::index_names() {
+            match document.get(index_name) {
+                Some(value) => index_filter.insert(*index_name, value.clone()),
+                None => {
+                    return Err(Error::MissingIndexField {
+                        collection: T::collection_name().to_string(),
+                        field: index_name.to_string(),
+                    })
+                }
+            };
+        }
+        filters.push(index_filter);
+    }
+    let subject = Bson::Array(filters.iter().map(subject_of).collect());
+    delete_roots_with_graph(
+        T::collection_name(),
+        filters,
+        subject,
+        graph,
+        db,
+        options,
+        None,
+    )
+    .await
+}
+
+/// `safe_delete_with_options` following `graph`, reporting to `progress` if there is one.
+pub(crate) async fn delete_with_graph<T: Schemable>(
+    to_delete: T,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+    options: &DeleteOptions,
+    progress: Option<Progress>,
+) -> Result<DeletionReceipt, Error> {
+    let index_filter = to_delete.index_filter()?;
+    delete_root_with_graph(
+        T::collection_name(),
+        index_filter,
+        graph,
+        db,
+        options,
+        progress,
+    )
+    .await
+}
+
+/// Deletes the document of `root_coll` matching `index_filter` and everything it owns, as
+/// `delete_with_graph` does for a `Schemable`.
+pub(crate) async fn delete_root_with_graph<'g>(
+    root_coll: &'g str,
+    index_filter: Document,
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+    db: &Database,
+    options: &DeleteOptions,
+    progress: Option<Progress>,
+) -> Result<DeletionReceipt, Error> {
+    let subject = subject_of(&index_filter);
+    delete_roots_with_graph(
+        root_coll,
+        vec![index_filter],
+        subject,
+        graph,
+        db,
+        options,
+        progress,
+    )
+    .await
+}
+
+/// Deletes the documents of `root_coll` matching any of `index_filters` and everything they
+/// own in a single cascade, recording `subject` as the subject of the receipt.
+async fn delete_roots_with_graph<'g>(
+    root_coll: &'g str,
+    index_filters: Vec<Document>,
+    subject: Bson,
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+    db: &Database,
+    options: &DeleteOptions,
+    progress: Option<Progress>,
+) -> Result<DeletionReceipt, Error> {
+    let mut executor = Executor::new(db, options.batch_size);
+    executor.progress = progress;
+    delete_subject(
+        root_coll,
+        index_filters,
+        subject,
+        graph,
+        options,
+        &mut executor,
+    )
+    .await
+}
+
+/// The subject of the receipt of a deletion by `index_filter`: its index value, or a
+/// document of its index fields for a composite index.
+fn subject_of(index_filter: &Document) -> Bson {
+    match index_filter.len() {
+        1 => index_filter.values().next().cloned().unwrap_or(Bson::Null),
+        _ => Bson::Document(index_filter.clone()),
+    }
+}
+
+/// Transactional variant of `safe_delete`: the whole cascade runs inside a single
+/// transaction on a new session of `client`, so either every document `to_delete` owns
+/// is removed along with it, or (if any step fails) nothing is.
+/// Transactions are only available on replica sets and sharded clusters, so this
+/// returns an error without touching any data when `db` is served by a standalone server.
+pub async fn safe_delete_transaction<T: Schemable>(
+    to_delete: T,
+    client: &Client,
+    db: &Database,
+) -> Result<DeletionReceipt, Error> {
+    safe_delete_transaction_with_options(to_delete, client, db, DeleteOptions::default()).await
+}
+
+/// Variant of `safe_delete_transaction` that disposes of `to_delete` and everything it owns
+/// according to `options`. The receipt is stored in the `audit_collection` as part of the
+/// same transaction, so it exists if and only if the deletion happened.
+pub async fn safe_delete_transaction_with_options<T: Schemable>(
+    to_delete: T,
+    client: &Client,
+    db: &Database,
+    options: DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    let graph = load_graph()?;
+    delete_transaction_with_graph(to_delete, &graph, client, db, &options).await
+}
+
+/// `safe_delete_transaction_with_options` following `graph`.
+pub(crate) async fn delete_transaction_with_graph<T: Schemable>(
+    to_delete: T,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    client: &Client,
+    db: &Database,
+    options: &DeleteOptions,
+) -> Result<DeletionReceipt, Error> {
+    if !supports_transactions(db).await? {
+        return Err(Error::TransactionsUnsupported {
+            database: db.name().to_string(),
+        });
+    }
+
+    let mut session = client.start_session(None).await?;
+    session.start_transaction(None).await?;
+    let mut executor = Executor {
+        db,
+        session: Some(&mut session),
+        batch_size: options.batch_size,
+        progress: None,
+    };
+    let index_filter = to_delete.index_filter()?;
+    let subject = subject_of(&index_filter);
+    let receipt = match delete_subject(
+        T::collection_name(),
+        vec![index_filter],
+        subject,
+        graph,
+        options,
+        &mut executor,
+    )
+    .await
+    {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            // The original error is more useful to the caller than a failure to abort.
+            let _ = session.abort_transaction().await;
+            return Err(e);
+        }
+    };
+    session.commit_transaction().await?;
+
+    Ok(receipt)
+}
+
+/// Returns whether the deployment serving `db` is a replica set member or a mongos, i.e.
+/// whether it can run multi-document transactions.
+async fn supports_transactions(db: &Database) -> Result<bool, Error> {
+    let hello = db.run_command(doc! { "hello": 1 }, None).await?;
+    Ok(hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"))
+}
+
+/// Deletes the documents of `root_coll` matching any of `index_filters` and everything they
+/// own according to `options`, using the queries of `executor`, and records the receipt of
+/// the deletion of `subject`.
+#[instrument(
+    name = "safe_delete",
+    skip_all,
+    fields(collection = root_coll, mode = ?options.mode)
+)]
+async fn delete_subject<'g>(
+    root_coll: &'g str,
+    index_filters: Vec<Document>,
+    subject: Bson,
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+    options: &DeleteOptions,
+    executor: &mut Executor<'_>,
+) -> Result<DeletionReceipt, Error> {
+    let started = Instant::now();
+    let embedded = load_embedded();
+    let cascade = Cascade::resolve(
+        root_coll,
+        index_filters.clone(),
+        graph,
+        &embedded,
+        &load_index_map(),
+        executor,
+    )
+    .await?;
+
+    let deleted_at = DateTime::now();
+    let deleted_counts = match options.mode {
+        DeleteMode::Hard => cascade.execute(executor).await?,
+        DeleteMode::Soft => cascade.tombstone(deleted_at, executor).await?,
+    };
+    let verified = options.verify && options.mode == DeleteMode::Hard;
+    if verified {
+        let verification = verify_subject(root_coll, index_filters, graph, executor).await?;
+        if !verification.is_clean() {
+            return Err(Error::Unverified(verification));
         }
-        let found_cursor = collection
-            .find(doc! { edge.owned_field: owner_id.unwrap() }, None)
-            .await?;
-        // Call safe_delete_document on each of the found results. Join all of the resulting promises.
-        let doc_vec: Vec<Document> = found_cursor.try_collect().await?;
-        let delete_promises: Vec<_> = doc_vec
-            .iter()
-            .map(|doc| safe_delete_children(doc, child_coll, &graph, &db))
-            .collect();
-        try_join_all(delete_promises).await?;
-        // Call delete_many on all found elements of our collection
-        collection
-            .delete_many(doc! { edge.owned_field: owner_id.unwrap() }, None)
-            .await?;
     }
+    let receipt = DeletionReceipt {
+        collection: root_coll.to_string(),
+        subject,
+        deleted_at,
+        mode: options.mode,
+        deleted_counts,
+        graph_version: graph_version(graph),
+        verified,
+    };
 
-    Ok(())
+    if let Some(audit_collection) = &options.audit_collection {
+        let document = mongodb::bson::to_document(&receipt).map_err(Error::Encode)?;
+        executor.insert_one(audit_collection, document).await?;
+    }
+    info!(
+        subject = %receipt.subject,
+        deleted = receipt.total_count(),
+        verified,
+        elapsed_ms = elapsed_ms(started),
+        "deleted subject"
+    );
+    Ok(receipt)
 }
@@ -0,0 +1,139 @@
+use crate::verify::Verification;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Errors returned by mongowner's deletion and graph loading functions.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The ownership graph generated by the `Schema` derive could not be read or parsed.
+    GraphLoad {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The ownership graph contains a cycle through `collection`, so the owners of its
+    /// documents cannot be told apart from the documents they own.
+    Cycle { collection: String },
+    /// A document of `collection` that is being deleted has no `field`, which documents it
+    /// owns use to reference it.
+    MissingOwnerField { collection: String, field: String },
+    /// A document of `collection` matched by `safe_delete_matching` has no `field`, one of
+    /// its index fields, so it can't be told apart from the documents it owns.
+    MissingIndexField { collection: String, field: String },
+    /// The `field` of a document of `collection` holds a value of BSON type `found`, which
+    /// cannot reference an owner.
+    TypeMismatch {
+        collection: String,
+        field: String,
+        found: String,
+    },
+    /// Deleting documents of `collection` is refused because `field` of documents of
+    /// `referencing_collection` still references them with `on_delete = restrict`.
+    Restricted {
+        collection: String,
+        referencing_collection: String,
+        field: String,
+    },
+    /// A deletion run with `DeleteOptions::verify` left the documents of the `Verification`
+    /// behind.
+    Unverified(Verification),
+    /// A deletion run by `safe_delete_with_progress` was cancelled after deleting the
+    /// documents counted in `deleted_counts`, by the name of their collection.
+    Cancelled {
+        deleted_counts: BTreeMap<String, u64>,
+    },
+    /// A transactional deletion was requested on `database`, which is served by a
+    /// standalone server that does not support transactions.
+    TransactionsUnsupported { database: String },
+    /// A document could not be encoded as BSON.
+    Encode(mongodb::bson::ser::Error),
+    /// The MongoDB driver returned an error.
+    Mongo(mongodb::error::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::GraphLoad { path, source } => {
+                write!(f, "could not load ownership graph from {:?}: {}", path, source)
+            }
+            Error::Cycle { collection } => write!(
+                f,
+                "ownership graph has a cycle through collection {}",
+                collection
+            ),
+            Error::MissingOwnerField { collection, field } => write!(
+                f,
+                "document of collection {} is missing owner field {}",
+                collection, field
+            ),
+            Error::MissingIndexField { collection, field } => write!(
+                f,
+                "document of collection {} is missing index field {}",
+                collection, field
+            ),
+            Error::TypeMismatch {
+                collection,
+                field,
+                found,
+            } => write!(
+                f,
+                "field {} of a document of collection {} holds a {}, which cannot reference an owner",
+                field, collection, found
+            ),
+            Error::Restricted {
+                collection,
+                referencing_collection,
+                field,
+            } => write!(
+                f,
+                "cannot delete from collection {}: documents are still referenced by field {} \
+                of collection {}, which restricts their deletion",
+                collection, field, referencing_collection
+            ),
+            Error::Unverified(verification) => write!(
+                f,
+                "deletion left {} documents behind, in collections {}",
+                verification.total_count(),
+                verification
+                    .remaining
+                    .keys()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::Cancelled { deleted_counts } => write!(
+                f,
+                "deletion was cancelled after deleting {} documents",
+                deleted_counts.values().sum::<u64>()
+            ),
+            Error::TransactionsUnsupported { database } => write!(
+                f,
+                "transactional deletion requires a replica set or sharded cluster, \
+                but database {} is served by a standalone server",
+                database
+            ),
+            Error::Encode(e) => write!(f, "could not encode document as BSON: {}", e),
+            Error::Mongo(e) => write!(f, "mongo error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::GraphLoad { source, .. } => Some(source.as_ref()),
+            Error::Encode(e) => Some(e),
+            Error::Mongo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        Error::Mongo(e)
+    }
+}
@@ -0,0 +1,278 @@
+use crate::error::Error;
+use crate::progress::{DeleteEvent, Progress};
+
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::{ClientSession, Database};
+use std::collections::{BTreeMap, HashSet};
+use tracing::trace;
+
+/// Issues the queries of a cascade, either directly against the database or, when a
+/// session is present, as part of the transaction running on that session.
+pub(crate) struct Executor<'a> {
+    pub(crate) db: &'a Database,
+    pub(crate) session: Option<&'a mut ClientSession>,
+    /// The most values a single `$in` query lists; longer lists are split over several
+    /// queries.
+    pub(crate) batch_size: usize,
+    /// Where the events of a deletion are reported, and whether it was cancelled.
+    pub(crate) progress: Option<Progress>,
+}
+
+impl<'a> Executor<'a> {
+    /// An executor issuing queries of `batch_size` values directly against `db`.
+    pub(crate) fn new(db: &'a Database, batch_size: usize) -> Self {
+        Executor {
+            db,
+            session: None,
+            batch_size,
+            progress: None,
+        }
+    }
+
+    /// Reports `event` to the progress of the deletion, if any.
+    pub(crate) fn emit(&self, event: impl FnOnce() -> DeleteEvent) {
+        if let Some(progress) = &self.progress {
+            progress.emit(event());
+        }
+    }
+
+    /// Whether the deletion was cancelled. Queries over several batches stop issuing them
+    /// once it is.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.progress.as_ref().is_some_and(Progress::is_cancelled)
+    }
+
+    /// Fails with `Error::Cancelled`, reporting `deleted_counts`, if the deletion was
+    /// cancelled.
+    pub(crate) fn check_cancelled(
+        &self,
+        deleted_counts: &BTreeMap<String, u64>,
+    ) -> Result<(), Error> {
+        match self.is_cancelled() {
+            true => Err(Error::Cancelled {
+                deleted_counts: deleted_counts.clone(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Splits `values` into batches of at most `batch_size`, dropping duplicates.
+    fn batches(&self, values: Vec<Bson>) -> Vec<Vec<Bson>> {
+        let mut seen = HashSet::new();
+        let values: Vec<Bson> = values
+            .into_iter()
+            .filter(|value| seen.insert(value.to_string()))
+            .collect();
+        values
+            .chunks(self.batch_size.max(1))
+            .map(|batch| batch.to_vec())
+            .collect()
+    }
+
+    /// Finds the documents of `collection_name` whose `field` holds one of `values` and that
+    /// also match `filter`, with one query per batch of values. With a `projection`, only the
+    /// projected fields of the documents are returned.
+    pub(crate) async fn find_in(
+        &mut self,
+        collection_name: &str,
+        field: &str,
+        values: Vec<Bson>,
+        filter: Document,
+        projection: Option<Document>,
+    ) -> mongodb::error::Result<Vec<Document>> {
+        let mut documents = Vec::new();
+        for batch in self.batches(values) {
+            let mut batch_filter = filter.clone();
+            batch_filter.insert(field, doc! { "$in": batch });
+            documents.extend(
+                self.find(collection_name, batch_filter, projection.clone())
+                    .await?,
+            );
+        }
+        Ok(documents)
+    }
+
+    /// Deletes the documents of `collection_name` whose `_id` is one of `ids`, one batch at
+    /// a time.
+    pub(crate) async fn delete_in(
+        &mut self,
+        collection_name: &str,
+        ids: Vec<Bson>,
+    ) -> mongodb::error::Result<u64> {
+        let mut deleted = 0;
+        for batch in self.batches(ids) {
+            if self.is_cancelled() {
+                break;
+            }
+            let count = self
+                .delete_many(collection_name, doc! { "_id": { "$in": batch } })
+                .await?;
+            self.emit(|| DeleteEvent::DocumentsDeleted {
+                collection: collection_name.to_string(),
+                count,
+            });
+            deleted += count;
+        }
+        Ok(deleted)
+    }
+
+    /// Applies `update` to the documents of `collection_name` whose `_id` is one of `ids`
+    /// and that also match `filter`, one batch at a time.
+    pub(crate) async fn update_in(
+        &mut self,
+        collection_name: &str,
+        ids: Vec<Bson>,
+        filter: Document,
+        update: Document,
+    ) -> mongodb::error::Result<u64> {
+        let mut modified = 0;
+        for batch in self.batches(ids) {
+            if self.is_cancelled() {
+                break;
+            }
+            let mut batch_filter = filter.clone();
+            batch_filter.insert("_id", doc! { "$in": batch });
+            modified += self
+                .update_many(collection_name, batch_filter, update.clone())
+                .await?;
+        }
+        Ok(modified)
+    }
+
+    /// Pulls the elements holding one of `values` out of the `array_field` arrays of
+    /// `collection_name`, one batch at a time. With an `element_field`, the elements are
+    /// sub-documents matched by the value of that field.
+    pub(crate) async fn pull_in(
+        &mut self,
+        collection_name: &str,
+        array_field: &str,
+        element_field: Option<&str>,
+        values: Vec<Bson>,
+    ) -> mongodb::error::Result<u64> {
+        let mut modified = 0;
+        for batch in self.batches(values) {
+            if self.is_cancelled() {
+                break;
+            }
+            let (filter, condition) = match element_field {
+                Some(element_field) => (
+                    doc! { format!("{}.{}", array_field, element_field): { "$in": batch.clone() } },
+                    doc! { element_field: { "$in": batch } },
+                ),
+                None => (
+                    doc! { array_field: { "$in": batch.clone() } },
+                    doc! { "$in": batch },
+                ),
+            };
+            modified += self
+                .update_many(
+                    collection_name,
+                    filter,
+                    doc! { "$pull": { array_field: condition } },
+                )
+                .await?;
+        }
+        Ok(modified)
+    }
+
+    pub(crate) async fn find(
+        &mut self,
+        collection_name: &str,
+        filter: Document,
+        projection: Option<Document>,
+    ) -> mongodb::error::Result<Vec<Document>> {
+        trace!(collection = collection_name, %filter, "find");
+        let collection = self.db.collection::<Document>(collection_name);
+        let options = FindOptions::builder().projection(projection).build();
+        match self.session.as_deref_mut() {
+            Some(session) => {
+                let mut cursor = collection
+                    .find_with_session(filter, options, session)
+                    .await?;
+                cursor.stream(session).try_collect().await
+            }
+            None => collection.find(filter, options).await?.try_collect().await,
+        }
+    }
+
+    pub(crate) async fn aggregate(
+        &mut self,
+        collection_name: &str,
+        pipeline: Vec<Document>,
+    ) -> mongodb::error::Result<Vec<Document>> {
+        trace!(collection = collection_name, pipeline = ?pipeline, "aggregate");
+        let collection = self.db.collection::<Document>(collection_name);
+        match self.session.as_deref_mut() {
+            Some(session) => {
+                let mut cursor = collection
+                    .aggregate_with_session(pipeline, None, session)
+                    .await?;
+                cursor.stream(session).try_collect().await
+            }
+            None => {
+                collection
+                    .aggregate(pipeline, None)
+                    .await?
+                    .try_collect()
+                    .await
+            }
+        }
+    }
+
+    pub(crate) async fn insert_one(
+        &mut self,
+        collection_name: &str,
+        document: Document,
+    ) -> mongodb::error::Result<()> {
+        trace!(collection = collection_name, "insert_one");
+        let collection = self.db.collection::<Document>(collection_name);
+        match self.session.as_deref_mut() {
+            Some(session) => {
+                collection
+                    .insert_one_with_session(document, None, session)
+                    .await?
+            }
+            None => collection.insert_one(document, None).await?,
+        };
+        Ok(())
+    }
+
+    pub(crate) async fn delete_many(
+        &mut self,
+        collection_name: &str,
+        filter: Document,
+    ) -> mongodb::error::Result<u64> {
+        trace!(collection = collection_name, %filter, "delete_many");
+        let collection = self.db.collection::<Document>(collection_name);
+        let result = match self.session.as_deref_mut() {
+            Some(session) => {
+                collection
+                    .delete_many_with_session(filter, None, session)
+                    .await?
+            }
+            None => collection.delete_many(filter, None).await?,
+        };
+        Ok(result.deleted_count)
+    }
+
+    pub(crate) async fn update_many(
+        &mut self,
+        collection_name: &str,
+        filter: Document,
+        update: Document,
+    ) -> mongodb::error::Result<u64> {
+        trace!(collection = collection_name, %filter, %update, "update_many");
+        let collection = self.db.collection::<Document>(collection_name);
+        let result = match self.session.as_deref_mut() {
+            Some(session) => {
+                collection
+                    .update_many_with_session(filter, update, None, session)
+                    .await?
+            }
+            None => collection.update_many(filter, update, None).await?,
+        };
+        Ok(result.modified_count)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::cascade::{bson_key, get_path, reachable_in_order};
+use crate::delete::{Schemable, DEFAULT_BATCH_SIZE};
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::util::*;
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use petgraph::{graphmap::GraphMap, Directed, Direction};
+use std::collections::{BTreeMap, HashSet};
+
+/// Every document a data subject owns, directly or indirectly, for answering access and
+/// portability requests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubjectExport {
+    /// The owned documents, grouped by the name of their collection. The subject's own
+    /// document is listed under its collection. Owned entries of `#[embedded_owned_by]`
+    /// arrays are grouped under `{collection}.{array_field}`, as documents holding the `_id`
+    /// of the document they are embedded in and an array of the owned entries only.
+    pub collections: BTreeMap<String, Vec<Document>>,
+}
+
+impl SubjectExport {
+    /// Total number of exported documents across all collections.
+    pub fn total_count(&self) -> usize {
+        self.collections.values().map(Vec::len).sum()
+    }
+
+    /// The export as a JSON object mapping collection names to arrays of documents, in
+    /// relaxed extended JSON so that values such as ObjectIds and dates survive the trip.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.collections
+                .iter()
+                .map(|(collection_name, documents)| {
+                    let documents = documents
+                        .iter()
+                        .map(|document| Bson::Document(document.clone()).into_relaxed_extjson())
+                        .collect();
+                    (collection_name.clone(), serde_json::Value::Array(documents))
+                })
+                .collect(),
+        )
+    }
+
+    /// The export as a BSON archive: a concatenation of `{ collection, document }` BSON
+    /// documents, one per exported document, which can be read back with
+    /// `Document::from_reader`. Unlike a single BSON document, the archive is not bound by
+    /// the 16MB document size limit.
+    pub fn to_bson_archive(&self) -> Result<Vec<u8>, Error> {
+        let mut archive = Vec::new();
+        for (collection_name, documents) in &self.collections {
+            for document in documents {
+                doc! { "collection": collection_name, "document": document }
+                    .to_writer(&mut archive)
+                    .map_err(Error::Encode)?;
+            }
+        }
+        Ok(archive)
+    }
+}
+
+/// Collects `subject` and every document it owns, following the same ownership graph as
+/// `safe_delete`. Documents that `subject` shares with other owners are included as well,
+/// along with everything they in turn own, since they hold the subject's data even though
+/// `safe_delete` would only unlink them. So are the entries of `#[embedded_owned_by]` arrays
+/// referencing exported documents, but not the rest of the documents holding them. Documents
+/// that merely `#[references]` the subject's data are not the subject's, so they are left
+/// out.
+pub async fn export_subject<T: Schemable>(
+    subject: &T,
+    db: &Database,
+) -> Result<SubjectExport, Error> {
+    let graph = load_graph()?;
+    export_with_graph(subject, &graph, db).await
+}
+
+/// `export_subject` following `graph`.
+pub(crate) async fn export_with_graph<T: Schemable>(
+    subject: &T,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+) -> Result<SubjectExport, Error> {
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+
+    let root_coll = T::collection_name();
+    let mut exported: BTreeMap<&str, Vec<Document>> = BTreeMap::new();
+    let roots = executor
+        .find(root_coll, subject.index_filter()?, None)
+        .await?;
+    exported.insert(root_coll, roots);
+
+    for (collection_name, _) in reachable_in_order(root_coll, graph)?.into_iter().skip(1) {
+        let mut documents = Vec::new();
+        let mut ids = HashSet::new();
+        for (_, owner_coll, edge) in graph
+            .edges_directed(collection_name, Direction::Outgoing)
+            .filter(|(_, _, edge)| edge.kind == EdgeKind::Owned)
+        {
+            let values: Vec<Bson> = exported
+                .get(owner_coll)
+                .into_iter()
+                .flatten()
+                .filter_map(|owner| owner.get(edge.owner_index).cloned())
+                .collect();
+            let found = executor
+                .find_in(
+                    collection_name,
+                    edge.owned_field,
+                    values,
+                    Document::new(),
+                    None,
+                )
+                .await?;
+            // A document owned through several edges is found once per edge
+            documents.extend(
+                found
+                    .into_iter()
+                    .filter(|document| ids.insert(document.get("_id").map(Bson::to_string))),
+            );
+        }
+        if !documents.is_empty() {
+            exported.insert(collection_name, documents);
+        }
+    }
+
+    // Embedded entries belong to the owners they reference, unlike the documents holding them
+    let mut embedded_entries = Vec::new();
+    for (collection_name, embedded) in load_embedded() {
+        let values: Vec<Bson> = exported
+            .get(embedded.owner_collection)
+            .into_iter()
+            .flatten()
+            .filter_map(|owner| owner.get(embedded.owner_index).cloned())
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        let owned: HashSet<String> = values.iter().map(bson_key).collect();
+        let field = match embedded.element_field {
+            Some(element_field) => format!("{}.{}", embedded.array_field, element_field),
+            None => embedded.array_field.to_string(),
+        };
+        let found = executor
+            .find_in(
+                collection_name,
+                &field,
+                values,
+                Document::new(),
+                Some(doc! { "_id": 1, embedded.array_field: 1 }),
+            )
+            .await?;
+
+        let mut documents = Vec::new();
+        let mut ids = HashSet::new();
+        for document in found {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+            let entries = match document.get(embedded.array_field) {
+                Some(Bson::Array(entries)) if ids.insert(bson_key(&id)) => entries,
+                _ => continue,
+            };
+            let mut owned_entries = Vec::new();
+            for entry in entries {
+                let reference = match (embedded.element_field, entry) {
+                    (Some(element_field), Bson::Document(entry)) => {
+                        get_path(entry, collection_name, element_field)?
+                    }
+                    (Some(_), _) => None,
+                    (None, entry) => Some(entry),
+                };
+                if reference.is_some_and(|reference| owned.contains(&bson_key(reference))) {
+                    owned_entries.push(entry.clone());
+                }
+            }
+            if !owned_entries.is_empty() {
+                documents.push(doc! { "_id": id, embedded.array_field: owned_entries });
+            }
+        }
+        if !documents.is_empty() {
+            embedded_entries.push((
+                format!("{}.{}", collection_name, embedded.array_field),
+                documents,
+            ));
+        }
+    }
+
+    Ok(SubjectExport {
+        collections: exported
+            .into_iter()
+            .filter(|(_, documents)| !documents.is_empty())
+            .map(|(collection_name, documents)| (collection_name.to_string(), documents))
+            .chain(embedded_entries)
+            .collect(),
+    })
+}
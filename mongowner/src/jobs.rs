@@ -0,0 +1,394 @@
+use crate::audit::DeletionReceipt;
+use crate::delete::{self, DeleteOptions, Schemable};
+use crate::error::Error;
+use crate::progress::{Cancellation, DeleteEvent, Progress};
+use crate::util::*;
+
+use futures::StreamExt;
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use petgraph::{graphmap::GraphMap, Directed};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+
+/// The collection mongowner keeps its deletion jobs in.
+pub const JOBS_COLLECTION: &str = "mongowner_jobs";
+
+/// Where a deletion job is at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a worker, either never run or run and failed fewer than
+    /// `WorkerOptions::max_attempts` times.
+    Pending,
+    /// Claimed by a worker. Once its lease expires, the worker is presumed dead and the job
+    /// may be resumed by another.
+    Running,
+    /// The deletion succeeded.
+    Completed,
+    /// The deletion failed `WorkerOptions::max_attempts` times.
+    Failed,
+}
+
+/// A deletion requested with `enqueue_delete`, as stored in `JOBS_COLLECTION`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeletionJob {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// The collection of the data subject.
+    pub collection: String,
+    /// The filter matching the data subject by its index fields.
+    pub subject_filter: Document,
+    pub options: DeleteOptions,
+    pub status: JobStatus,
+    /// Number of times a worker claimed the job.
+    pub attempts: u32,
+    /// The worker running the job, while it is running.
+    pub worker: Option<String>,
+    /// When the worker running the job is presumed dead, unless it reports progress first.
+    pub lease_expires_at: Option<DateTime>,
+    /// The collection the running job is deleting from.
+    pub current_collection: Option<String>,
+    /// Number of documents deleted (or tombstoned) so far, over every attempt, by the name
+    /// of their collection. Stored as an array of `{ collection, count }` documents, since
+    /// collection names may hold dots.
+    #[serde(with = "count_entries")]
+    pub deleted_counts: BTreeMap<String, u64>,
+    /// The error the last failed attempt ended with.
+    pub error: Option<String>,
+    /// The receipt of the attempt that completed the job. Its counts only cover that
+    /// attempt.
+    pub receipt: Option<DeletionReceipt>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+/// How a worker runs deletion jobs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkerOptions {
+    /// The name recorded on the jobs the worker claims.
+    pub worker: String,
+    /// How long a job stays claimed by the worker without it reporting progress. The worker
+    /// also renews it every third of the lease while the job runs. A job whose lease expired
+    /// is resumed by the next worker looking for one, and the worker that lost it stops.
+    pub lease: Duration,
+    /// The number of attempts after which a failing job is marked `JobStatus::Failed`
+    /// instead of being retried.
+    pub max_attempts: u32,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        WorkerOptions {
+            worker: format!("worker-{}", ObjectId::new().to_hex()),
+            lease: Duration::from_secs(300),
+            max_attempts: 3,
+        }
+    }
+}
+
+/// Records a request to delete `to_delete` according to `options` as a pending job, to be
+/// run by `run_next_job`, and returns its id.
+pub async fn enqueue_delete<T: Schemable>(
+    to_delete: &T,
+    db: &Database,
+    options: DeleteOptions,
+) -> Result<ObjectId, Error> {
+    let now = DateTime::now();
+    let job = DeletionJob {
+        id: ObjectId::new(),
+        collection: T::collection_name().to_string(),
+        subject_filter: to_delete.index_filter()?,
+        options,
+        status: JobStatus::Pending,
+        attempts: 0,
+        worker: None,
+        lease_expires_at: None,
+        current_collection: None,
+        deleted_counts: BTreeMap::new(),
+        error: None,
+        receipt: None,
+        created_at: now,
+        updated_at: now,
+    };
+    jobs_collection(db).insert_one(&job, None).await?;
+    Ok(job.id)
+}
+
+/// The deletion job `id`, if it exists.
+pub async fn find_job(id: ObjectId, db: &Database) -> Result<Option<DeletionJob>, Error> {
+    Ok(jobs_collection(db)
+        .find_one(doc! { "_id": id }, None)
+        .await?)
+}
+
+/// Claims the oldest pending job, or a running one whose worker's lease expired, and runs
+/// it to completion or failure, returning the job as it ended up. Returns `None` if there is
+/// no job to run.
+///
+/// Running a job again is safe: owned documents are deleted before their owners and the
+/// subject last, and the cascade is resolved from the stored index filter, so a resumed job
+/// picks up the documents left by an interrupted one even if they no longer reference an
+/// existing subject.
+pub async fn run_next_job(
+    db: &Database,
+    worker: &WorkerOptions,
+) -> Result<Option<DeletionJob>, Error> {
+    let graph = load_graph()?;
+    run_next_with_graph(&graph, db, worker).await
+}
+
+/// Runs jobs with `run_next_job` until none is left to run, returning them.
+pub async fn run_pending_jobs(
+    db: &Database,
+    worker: &WorkerOptions,
+) -> Result<Vec<DeletionJob>, Error> {
+    let graph = load_graph()?;
+    run_pending_with_graph(&graph, db, worker).await
+}
+
+/// `run_pending_jobs` following `graph`.
+pub(crate) async fn run_pending_with_graph(
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+    worker: &WorkerOptions,
+) -> Result<Vec<DeletionJob>, Error> {
+    let mut jobs = Vec::new();
+    while let Some(job) = run_next_with_graph(graph, db, worker).await? {
+        jobs.push(job);
+    }
+    Ok(jobs)
+}
+
+/// `run_next_job` following `graph`.
+#[instrument(name = "run_job", skip_all, fields(worker = %worker.worker))]
+pub(crate) async fn run_next_with_graph(
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+    worker: &WorkerOptions,
+) -> Result<Option<DeletionJob>, Error> {
+    let jobs = jobs_collection(db);
+    let now = DateTime::now();
+    let claimable = doc! {
+        "$or": [
+            { "status": "pending" },
+            { "status": "running", "lease_expires_at": { "$lte": now } },
+        ]
+    };
+    let claim = doc! {
+        "$set": {
+            "status": "running",
+            "worker": &worker.worker,
+            "lease_expires_at": lease_expiry(worker),
+            "updated_at": now,
+        },
+        "$inc": { "attempts": 1 },
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+    let job = match jobs.find_one_and_update(claimable, claim, options).await? {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+    info!(
+        job = %job.id,
+        collection = %job.collection,
+        attempt = job.attempts,
+        "claimed deletion job"
+    );
+
+    // Progress is recorded as the deletion goes, which also keeps the lease alive. So does a
+    // heartbeat, since resolving a large cascade reports nothing for a while. If another
+    // worker took the job over meanwhile, this one stops deleting.
+    let cancellation = Cancellation::new();
+    let (progress, mut events) = Progress::new(cancellation.clone());
+    let deletion = delete::delete_root_with_graph(
+        &job.collection,
+        job.subject_filter.clone(),
+        graph,
+        db,
+        &job.options,
+        Some(progress),
+    );
+    let record = async {
+        let mut heartbeat = tokio::time::interval((worker.lease / 3).max(Duration::from_millis(1)));
+        heartbeat.tick().await;
+        loop {
+            let event = tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => Some(event),
+                    None => break,
+                },
+                _ = heartbeat.tick() => None,
+            };
+            match record_progress(&jobs, &job, worker, event).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(job = %job.id, "deletion job was taken over by another worker");
+                    cancellation.cancel();
+                    break;
+                }
+                Err(e) => {
+                    warn!(job = %job.id, error = %e, "could not record deletion job progress")
+                }
+            }
+        }
+    };
+    let (result, ()) = futures::join!(deletion, record);
+
+    let mut finish = doc! {
+        "worker": Bson::Null,
+        "lease_expires_at": Bson::Null,
+        "current_collection": Bson::Null,
+        "updated_at": DateTime::now(),
+    };
+    match result {
+        Ok(receipt) => {
+            info!(job = %job.id, deleted = receipt.total_count(), "completed deletion job");
+            let receipt = bson::to_bson(&receipt).map_err(Error::Encode)?;
+            finish.insert("status", "completed");
+            finish.insert("receipt", receipt);
+            finish.insert("error", Bson::Null);
+        }
+        Err(e) => {
+            let status = match job.attempts >= worker.max_attempts {
+                true => "failed",
+                false => "pending",
+            };
+            warn!(job = %job.id, error = %e, status, "deletion job attempt failed");
+            finish.insert("status", status);
+            finish.insert("error", e.to_string());
+        }
+    }
+    // The job is only finished by the worker still holding it
+    let finished = jobs
+        .update_one(
+            doc! { "_id": job.id, "worker": &worker.worker },
+            doc! { "$set": finish },
+            None,
+        )
+        .await?;
+    if finished.matched_count == 0 {
+        warn!(job = %job.id, "deletion job was taken over by another worker, leaving it to them");
+    }
+    find_job(job.id, db).await
+}
+
+/// Records `event` of the deletion run by `job`, if any, and extends the lease of `worker` on
+/// it. Returns whether `worker` still holds the job, i.e. no other worker claimed it after
+/// its lease expired.
+async fn record_progress(
+    jobs: &Collection<DeletionJob>,
+    job: &DeletionJob,
+    worker: &WorkerOptions,
+    event: Option<DeleteEvent>,
+) -> Result<bool, Error> {
+    let mut set = doc! {
+        "lease_expires_at": lease_expiry(worker),
+        "updated_at": DateTime::now(),
+    };
+    // The update is a pipeline, in which strings starting with `$` are field paths, so
+    // collection names are given as literals
+    match event {
+        Some(DeleteEvent::CollectionEntered { collection, .. }) => {
+            set.insert("current_collection", doc! { "$literal": collection });
+        }
+        Some(DeleteEvent::DocumentsDeleted { collection, count }) => {
+            set.insert("deleted_counts", add_deleted_count(&collection, count));
+        }
+        _ => {}
+    }
+    let recorded = jobs
+        .update_one(
+            doc! { "_id": job.id, "worker": &worker.worker },
+            vec![doc! { "$set": set }],
+            None,
+        )
+        .await?;
+    Ok(recorded.matched_count > 0)
+}
+
+/// The expression adding `count` to the entry of `collection` in the `deleted_counts` of a
+/// job, or appending one if it has none yet.
+fn add_deleted_count(collection: &str, count: u64) -> Document {
+    let collection = doc! { "$literal": collection };
+    let count = count as i64;
+    doc! {
+        "$cond": {
+            "if": { "$in": [&collection, "$deleted_counts.collection"] },
+            "then": {
+                "$map": {
+                    "input": "$deleted_counts",
+                    "as": "entry",
+                    "in": {
+                        "$cond": {
+                            "if": { "$eq": ["$$entry.collection", &collection] },
+                            "then": {
+                                "collection": "$$entry.collection",
+                                "count": { "$add": ["$$entry.count", count] },
+                            },
+                            "else": "$$entry",
+                        }
+                    },
+                }
+            },
+            "else": {
+                "$concatArrays": [
+                    "$deleted_counts",
+                    [{ "collection": &collection, "count": count }],
+                ]
+            },
+        }
+    }
+}
+
+/// When a lease of `worker` taken now expires.
+fn lease_expiry(worker: &WorkerOptions) -> DateTime {
+    let lease_millis = worker.lease.as_millis() as i64;
+    DateTime::from_millis(
+        DateTime::now()
+            .timestamp_millis()
+            .saturating_add(lease_millis),
+    )
+}
+
+fn jobs_collection(db: &Database) -> Collection<DeletionJob> {
+    db.collection(JOBS_COLLECTION)
+}
+
+/// Serializes counts by collection name as an array of `{ collection, count }` documents.
+mod count_entries {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize)]
+    struct Entry {
+        collection: String,
+        count: u64,
+    }
+
+    pub(super) fn serialize<S: Serializer>(
+        counts: &BTreeMap<String, u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(counts.iter().map(|(collection, &count)| Entry {
+            collection: collection.clone(),
+            count,
+        }))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, u64>, D::Error> {
+        let entries = Vec::<Entry>::deserialize(deserializer)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| (entry.collection, entry.count))
+            .collect())
+    }
+}
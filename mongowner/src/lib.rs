@@ -1,9 +1,42 @@
 pub mod util;
 
+pub mod audit;
+
+pub mod context;
+
 pub mod delete;
 
-pub use delete::Schemable;
+pub mod error;
+
+pub mod export;
+
+pub mod jobs;
+
+pub mod orphans;
+
+pub mod plan;
+
+pub mod progress;
+
+pub mod registry;
+
+pub mod retention;
+
+pub mod verify;
+
+mod cascade;
+
+mod executor;
+
+pub use context::Mongowner;
+
+pub use delete::{Schemable, SchemableExt};
+
+pub use error::Error;
 
 pub use mongowner_macros::Schema;
 
 pub use mongodb as mongo;
+
+#[doc(hidden)]
+pub use inventory;
@@ -0,0 +1,234 @@
+use crate::cascade::Cascade;
+use crate::delete::DEFAULT_BATCH_SIZE;
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::registry::EmbeddedOwnedBy;
+use crate::util::*;
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use petgraph::{graphmap::GraphMap, Directed, Direction};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, info, instrument, warn};
+
+/// The orphaned documents of a database: documents of owned collections none of whose
+/// owners exist any more, e.g. because they were deleted without `safe_delete`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct OrphanReport {
+    /// The `_id`s of the orphaned documents, by the name of their collection.
+    pub orphans: BTreeMap<String, Vec<Bson>>,
+    /// Number of documents deleted by `delete_orphans`, by the name of their collection,
+    /// including the documents the orphans owned. Empty for `find_orphans`.
+    pub deleted_counts: BTreeMap<String, u64>,
+    /// The orphans `delete_orphans` failed to delete, e.g. because a document that outlives
+    /// them references them with `on_delete = restrict`. The other orphans are deleted
+    /// regardless.
+    pub failures: Vec<OrphanFailure>,
+}
+
+/// An orphan that `delete_orphans` failed to delete.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrphanFailure {
+    /// The name of the orphan's collection.
+    pub collection: String,
+    /// The `_id` of the orphan.
+    pub id: Bson,
+    /// Why deleting the orphan failed.
+    pub error: String,
+}
+
+impl OrphanReport {
+    /// Total number of orphaned documents across all collections.
+    pub fn total_count(&self) -> usize {
+        self.orphans.values().map(Vec::len).sum()
+    }
+
+    fn add_counts(&mut self, counts: BTreeMap<String, u64>) {
+        for (collection_name, count) in counts {
+            *self.deleted_counts.entry(collection_name).or_default() += count;
+        }
+    }
+
+    fn add_failure(&mut self, collection_name: &str, id: Bson, error: Error) {
+        warn!(collection = collection_name, %id, %error, "could not delete orphan");
+        self.failures.push(OrphanFailure {
+            collection: collection_name.to_string(),
+            id,
+            error: error.to_string(),
+        });
+    }
+}
+
+/// Scans every owned collection of the ownership graph of this binary for orphaned
+/// documents, without modifying the database.
+pub async fn find_orphans(db: &Database) -> Result<OrphanReport, Error> {
+    let graph = load_graph()?;
+    find_orphans_with_graph(&graph, db).await
+}
+
+/// Variant of `find_orphans` scanning the collections of `graph`, such as one read by
+/// `load_graph_file`.
+#[instrument(name = "find_orphans", skip_all)]
+pub async fn find_orphans_with_graph(
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+) -> Result<OrphanReport, Error> {
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+    let mut report = OrphanReport::default();
+    for collection_name in cascade_order(graph)?.into_iter().rev() {
+        let orphans = collection_orphans(collection_name, graph, &mut executor).await?;
+        debug!(
+            collection = collection_name,
+            orphans = orphans.len(),
+            "scanned collection for orphans"
+        );
+        if !orphans.is_empty() {
+            report.orphans.insert(collection_name.to_string(), orphans);
+        }
+    }
+    info!(orphans = report.total_count(), "found orphans");
+    Ok(report)
+}
+
+/// Finds the orphaned documents of the ownership graph of this binary and deletes each of
+/// them as `safe_delete` would, along with everything it owns. An orphan that can't be
+/// deleted is recorded in the report's `failures`, and doesn't stop the others from being
+/// deleted.
+pub async fn delete_orphans(db: &Database) -> Result<OrphanReport, Error> {
+    let graph = load_graph()?;
+    delete_orphans_with_graph(&graph, &load_embedded(), db).await
+}
+
+/// Variant of `delete_orphans` cleaning up the collections of `graph` and the
+/// `#[embedded_owned_by]` arrays of `embedded`, such as ones read by `load_graph_file` and
+/// `load_embedded_file`. The orphans of each collection are deleted with one cascade per
+/// batch of `DEFAULT_BATCH_SIZE` orphans.
+#[instrument(name = "delete_orphans", skip_all)]
+pub async fn delete_orphans_with_graph<'g>(
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+    embedded: &[(&'g str, EmbeddedOwnedBy<'g>)],
+    db: &Database,
+) -> Result<OrphanReport, Error> {
+    let mut report = find_orphans_with_graph(graph, db).await?;
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+
+    // Owners come first, so an orphan may already be gone along with an orphaned owner
+    for collection_name in cascade_order(graph)?.into_iter().rev() {
+        let ids = match report.orphans.get(collection_name) {
+            Some(ids) => ids.clone(),
+            None => continue,
+        };
+        let existing = executor
+            .find_in(
+                collection_name,
+                "_id",
+                ids,
+                Document::new(),
+                Some(doc! { "_id": 1 }),
+            )
+            .await?;
+        let ids: Vec<Bson> = existing
+            .into_iter()
+            .filter_map(|orphan| orphan.get("_id").cloned())
+            .collect();
+        for batch in ids.chunks(executor.batch_size.max(1)) {
+            let deleted =
+                delete_orphan_batch(collection_name, batch, graph, embedded, &mut executor).await;
+            match deleted {
+                Ok(counts) => report.add_counts(counts),
+                // A failed batch is retried one orphan at a time, to tell which of them failed
+                // and still delete the others. A restricted orphan fails the batch while it is
+                // resolved, before anything is written.
+                Err(_) if batch.len() > 1 => {
+                    for id in batch {
+                        let deleted = delete_orphan_batch(
+                            collection_name,
+                            std::slice::from_ref(id),
+                            graph,
+                            embedded,
+                            &mut executor,
+                        )
+                        .await;
+                        match deleted {
+                            Ok(counts) => report.add_counts(counts),
+                            Err(e) => report.add_failure(collection_name, id.clone(), e),
+                        }
+                    }
+                }
+                Err(e) => report.add_failure(collection_name, batch[0].clone(), e),
+            }
+        }
+    }
+    info!(
+        deleted = report.deleted_counts.values().sum::<u64>(),
+        failed = report.failures.len(),
+        "deleted orphans"
+    );
+    Ok(report)
+}
+
+/// Deletes the orphans of `collection_name` with the `_id`s `ids` and everything they own
+/// in one cascade.
+async fn delete_orphan_batch<'g>(
+    collection_name: &'g str,
+    ids: &[Bson],
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+    embedded: &[(&'g str, EmbeddedOwnedBy<'g>)],
+    executor: &mut Executor<'_>,
+) -> Result<BTreeMap<String, u64>, Error> {
+    let filters = ids.iter().map(|id| doc! { "_id": id.clone() }).collect();
+    // Orphans are reported by `_id` alone, so their indexes aren't fetched, which also holds
+    // for graph files of other binaries
+    let cascade = Cascade::resolve(
+        collection_name,
+        filters,
+        graph,
+        embedded,
+        &HashMap::new(),
+        executor,
+    )
+    .await?;
+    cascade.execute(executor).await
+}
+
+/// The `_id`s of the documents of `collection_name` that reference no existing owner,
+/// found with a `$lookup` of each of its owner collections. Collections without owners
+/// have no orphans.
+async fn collection_orphans(
+    collection_name: &str,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    executor: &mut Executor<'_>,
+) -> Result<Vec<Bson>, Error> {
+    let owner_edges: Vec<(&str, &OwnEdge)> = graph
+        .edges_directed(collection_name, Direction::Outgoing)
+        .filter(|(_, _, edge)| edge.kind == EdgeKind::Owned)
+        .map(|(_, owner_coll, edge)| (owner_coll, edge))
+        .collect();
+    if owner_edges.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut pipeline: Vec<Document> = Vec::new();
+    let mut no_owners = Document::new();
+    for (i, (owner_coll, edge)) in owner_edges.iter().enumerate() {
+        let owners = format!("_mongowner_owners{}", i);
+        pipeline.push(doc! {
+            "$lookup": {
+                "from": *owner_coll,
+                "localField": edge.owned_field,
+                "foreignField": edge.owner_index,
+                "as": &owners,
+            }
+        });
+        no_owners.insert(owners, doc! { "$size": 0 });
+    }
+    pipeline.push(doc! { "$match": no_owners });
+    pipeline.push(doc! { "$project": { "_id": 1 } });
+
+    let orphans = executor.aggregate(collection_name, pipeline).await?;
+    Ok(orphans
+        .into_iter()
+        .filter_map(|orphan| orphan.get("_id").cloned())
+        .collect())
+}
@@ -0,0 +1,224 @@
+use crate::cascade::{bson_key, Cascade};
+use crate::delete::{Schemable, DEFAULT_BATCH_SIZE};
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::util::*;
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use petgraph::{graphmap::GraphMap, Directed};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A dry-run description of everything `safe_delete` would change for a data subject.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DeletePlan {
+    /// The affected documents, grouped by the name of their collection.
+    pub collections: BTreeMap<String, CollectionPlan>,
+    /// The `#[embedded_owned_by]` entries that would be pulled, grouped by
+    /// `{collection}.{array_field}`.
+    pub embedded: BTreeMap<String, EmbeddedPlan>,
+}
+
+/// The documents of a single collection that would be deleted or unlinked.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CollectionPlan {
+    /// Names of the index fields whose values are listed in `index_values`. For a composite
+    /// index, each value is a document holding all of these fields.
+    pub index_names: Vec<String>,
+    /// Distance of this collection from the data subject in the ownership tree, where the
+    /// subject's own collection has depth 0. If the collection is owned along several paths,
+    /// this is the shortest one. A collection outside the tree that only has references
+    /// cleared is one level below the closest collection it references.
+    pub depth: usize,
+    /// Index values of the documents that would be deleted.
+    pub index_values: Vec<Bson>,
+    /// Index values of the documents that are shared with an owner that is not deleted, so
+    /// would only lose their references to deleted owners.
+    pub unlinked_values: Vec<Bson>,
+}
+
+/// The entries of an `#[embedded_owned_by]` array that would be pulled out of the documents
+/// holding them.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EmbeddedPlan {
+    /// Names of the index fields whose values are listed in `index_values`.
+    pub index_names: Vec<String>,
+    /// Index values of the documents whose array holds entries that would be pulled.
+    pub index_values: Vec<Bson>,
+    /// Values of the owner index that the pulled entries reference.
+    pub owner_values: Vec<Bson>,
+}
+
+impl CollectionPlan {
+    /// Number of documents that would be deleted from this collection.
+    pub fn count(&self) -> usize {
+        self.index_values.len()
+    }
+}
+
+impl DeletePlan {
+    /// Total number of documents that would be deleted across all collections.
+    pub fn total_count(&self) -> usize {
+        self.collections.values().map(CollectionPlan::count).sum()
+    }
+}
+
+/// Reports what `safe_delete(to_plan, db)` would do, resolving the same cascade but without
+/// modifying the database.
+pub async fn plan_delete<T: Schemable>(to_plan: &T, db: &Database) -> Result<DeletePlan, Error> {
+    let graph = load_graph()?;
+    plan_with_graph(to_plan, &graph, db).await
+}
+
+/// `plan_delete` following `graph`.
+pub(crate) async fn plan_with_graph<T: Schemable>(
+    to_plan: &T,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+) -> Result<DeletePlan, Error> {
+    let index_map = load_index_map();
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+
+    let cascade = Cascade::resolve(
+        T::collection_name(),
+        vec![to_plan.index_filter()?],
+        graph,
+        &load_embedded(),
+        &index_map,
+        &mut executor,
+    )
+    .await?;
+
+    // Collections the cascade doesn't reach can still have references to deleted documents
+    // cleared, and are reported one level below the closest collection they reference
+    let mut collections = cascade.order.clone();
+    let mut unreached: Vec<&str> = cascade
+        .unlinked
+        .keys()
+        .copied()
+        .filter(|name| !collections.iter().any(|&(reached, _)| reached == *name))
+        .collect();
+    unreached.sort_unstable();
+    for collection_name in unreached {
+        let depth = graph
+            .neighbors(collection_name)
+            .filter_map(|referenced| {
+                cascade
+                    .order
+                    .iter()
+                    .find(|&&(reached, _)| reached == referenced)
+            })
+            .map(|&(_, depth)| depth + 1)
+            .min()
+            .unwrap_or(1);
+        collections.push((collection_name, depth));
+    }
+
+    let mut plan = DeletePlan::default();
+    for &(collection_name, depth) in &collections {
+        let index_names = index_names(collection_name, &index_map);
+        // Only report the root if it actually exists
+        let deleted = if depth == 0 {
+            &cascade.roots
+        } else {
+            match cascade.deleted.get(collection_name) {
+                Some(deleted) => deleted,
+                None => &Vec::new(),
+            }
+        };
+        let unlinked = cascade.unlinked.get(collection_name).into_iter().flatten();
+        let collection_plan = CollectionPlan {
+            index_names: index_names.iter().map(|name| name.to_string()).collect(),
+            depth,
+            index_values: deleted
+                .iter()
+                .map(|document| index_value(document, index_names))
+                .collect(),
+            unlinked_values: unlinked
+                .map(|(document, _)| index_value(document, index_names))
+                .collect(),
+        };
+        if collection_plan.count() > 0 || !collection_plan.unlinked_values.is_empty() {
+            plan.collections
+                .insert(collection_name.to_string(), collection_plan);
+        }
+    }
+
+    for (collection_name, embedded, values) in cascade.embedded {
+        let index_names = index_names(collection_name, &index_map);
+        let field = match embedded.element_field {
+            Some(element_field) => format!("{}.{}", embedded.array_field, element_field),
+            None => embedded.array_field.to_string(),
+        };
+        let mut projection = doc! { "_id": 1 };
+        for &index_name in index_names {
+            projection.insert(index_name, 1);
+        }
+        let holding = executor
+            .find_in(
+                collection_name,
+                &field,
+                values.clone(),
+                Document::new(),
+                Some(projection),
+            )
+            .await?;
+        // Documents deleted by the cascade are already reported with their collection
+        let deleted: HashSet<String> = plan
+            .collections
+            .get(collection_name)
+            .into_iter()
+            .flat_map(|collection_plan| &collection_plan.index_values)
+            .map(bson_key)
+            .collect();
+        let index_values: Vec<Bson> = holding
+            .iter()
+            .map(|document| index_value(document, index_names))
+            .filter(|value| !deleted.contains(&bson_key(value)))
+            .collect();
+        if index_values.is_empty() {
+            continue;
+        }
+        plan.embedded.insert(
+            format!("{}.{}", collection_name, embedded.array_field),
+            EmbeddedPlan {
+                index_names: index_names.iter().map(|name| name.to_string()).collect(),
+                index_values,
+                owner_values: values,
+            },
+        );
+    }
+
+    Ok(plan)
+}
+
+/// Names of the index fields of `collection_name`, defaulting to Mongo's `_id` for
+/// collections without a `Schema` struct.
+fn index_names(
+    collection_name: &str,
+    index_map: &HashMap<&'static str, &'static [&'static str]>,
+) -> &'static [&'static str] {
+    index_map.get(collection_name).copied().unwrap_or(&["_id"])
+}
+
+/// Value of the index of `document`: the value of its single index field (or its `_id` if
+/// it has none), or a document of the fields of a composite index.
+fn index_value(document: &Document, index_names: &[&str]) -> Bson {
+    match index_names {
+        [index_name] => document
+            .get(index_name)
+            .or_else(|| document.get("_id"))
+            .cloned()
+            .unwrap_or(Bson::Null),
+        _ => Bson::Document(
+            index_names
+                .iter()
+                .map(|name| {
+                    let value = document.get(name).cloned().unwrap_or(Bson::Null);
+                    (name.to_string(), value)
+                })
+                .collect(),
+        ),
+    }
+}
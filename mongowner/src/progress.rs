@@ -0,0 +1,126 @@
+use crate::audit::DeletionReceipt;
+use crate::delete::{self, DeleteOptions, Schemable};
+use crate::error::Error;
+use crate::util::*;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::Stream;
+use mongodb::Database;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// A step of a deletion run by `safe_delete_with_progress`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum DeleteEvent {
+    /// The cascade was resolved, before any document was touched. Holds the number of
+    /// documents to delete (or, for a soft deletion, to tombstone) by the name of their
+    /// collection.
+    Resolved { counts: BTreeMap<String, u64> },
+    /// The deletion started on the documents of `collection`, which is `depth` ownership
+    /// edges away from the deleted subject.
+    CollectionEntered { collection: String, depth: usize },
+    /// `count` documents of `collection` were deleted. Hard deletions report every batch of
+    /// documents, soft deletions every collection.
+    DocumentsDeleted { collection: String, count: u64 },
+    /// Every collection `depth` ownership edges away from the deleted subject is done.
+    LevelCompleted { depth: usize },
+}
+
+/// Cooperative cancellation of a deletion run by `safe_delete_with_progress`. Clones share
+/// their state, so a deletion can be cancelled from another task.
+///
+/// A cancelled deletion stops before its next batch, so the documents it already deleted
+/// stay deleted, and fails with `Error::Cancelled`, which counts them. Since owned
+/// documents are deleted before their owners, it leaves no orphans behind.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Cancellation::default()
+    }
+
+    /// Asks the deletion to stop before its next batch.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The stream of events of a deletion run by `safe_delete_with_progress`, which ends when
+/// the deletion does.
+#[derive(Debug)]
+pub struct ProgressEvents {
+    receiver: UnboundedReceiver<DeleteEvent>,
+}
+
+impl Stream for ProgressEvents {
+    type Item = DeleteEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DeleteEvent>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Where a deletion reports its events and learns of its cancellation.
+#[derive(Clone, Debug)]
+pub(crate) struct Progress {
+    events: UnboundedSender<DeleteEvent>,
+    cancellation: Cancellation,
+}
+
+impl Progress {
+    /// A progress reporting to the returned stream and stopping once `cancellation` is
+    /// cancelled.
+    pub(crate) fn new(cancellation: Cancellation) -> (Self, ProgressEvents) {
+        let (events, receiver) = mpsc::unbounded();
+        (
+            Progress {
+                events,
+                cancellation,
+            },
+            ProgressEvents { receiver },
+        )
+    }
+
+    pub(crate) fn emit(&self, event: DeleteEvent) {
+        // Nobody listening is no reason to stop deleting
+        let _ = self.events.unbounded_send(event);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+}
+
+/// Variant of `safe_delete_with_options` that reports its progress. Returns the stream of
+/// its events along with the deletion itself, which does nothing until it is awaited; the
+/// stream has to be consumed concurrently, e.g. with `futures::join!` or by spawning the
+/// deletion. Cancelling `cancellation` stops the deletion between two batches, failing it
+/// with `Error::Cancelled`.
+pub fn safe_delete_with_progress<'a, T: Schemable + 'a>(
+    to_delete: T,
+    db: &'a Database,
+    options: DeleteOptions,
+    cancellation: Cancellation,
+) -> (
+    ProgressEvents,
+    impl Future<Output = Result<DeletionReceipt, Error>> + 'a,
+) {
+    let (progress, events) = Progress::new(cancellation);
+    let deletion = async move {
+        let graph = load_graph()?;
+        delete::delete_with_graph(to_delete, &graph, db, &options, Some(progress)).await
+    };
+    (events, deletion)
+}
@@ -0,0 +1,181 @@
+use crate::error::Error;
+use crate::util::stable_hash;
+
+use mongodb::bson::Bson;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// An `#[owned_by(owner_collection, owner_index)]` annotation on the `owned_field` of a
+/// struct deriving `Schema`.
+#[derive(Clone, Copy, Debug)]
+pub struct OwnedBy {
+    pub owner_collection: &'static str,
+    pub owner_index: &'static str,
+    pub owned_field: &'static str,
+}
+
+/// What happens to the documents referencing a document that is deleted through a
+/// `#[references(_)]` annotation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnDelete {
+    /// Refuse to delete the referenced document.
+    Restrict,
+    /// Clear the reference: set it to null, or pull it out of an array of references.
+    SetNull,
+    /// Delete the referencing documents too, along with everything they own.
+    Cascade,
+}
+
+/// A `#[references(referenced_collection, referenced_index, on_delete = _)]` annotation on
+/// the `referencing_field` of a struct deriving `Schema`. Unlike `owned_by`, the referencing
+/// documents don't belong to the referenced one.
+#[derive(Clone, Copy, Debug)]
+pub struct References {
+    pub referenced_collection: &'static str,
+    pub referenced_index: &'static str,
+    pub referencing_field: &'static str,
+    pub on_delete: OnDelete,
+}
+
+/// An `#[embedded_owned_by(owner_collection, owner_index)]` annotation on the `array_field`
+/// of a struct deriving `Schema`. The elements of the array (or the value at `element_field`
+/// of each element) reference their owners, and are pulled out of the array when their
+/// owner is deleted, while the document holding them is kept.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct EmbeddedOwnedBy<'a> {
+    pub owner_collection: &'a str,
+    pub owner_index: &'a str,
+    pub array_field: &'a str,
+    #[serde(borrow)]
+    pub element_field: Option<&'a str>,
+}
+
+/// The ownership details of a struct deriving `Schema`. The derive registers one of these
+/// for every struct, so the ownership graph is compiled into the binary rather than read
+/// from disk at runtime.
+#[derive(Debug)]
+pub struct SchemaEntry {
+    pub struct_name: &'static str,
+    pub collection_name: &'static str,
+    /// The names of the index fields in Mongo; several for a composite index.
+    pub index_names: &'static [&'static str],
+    pub owned_by: &'static [OwnedBy],
+    pub embedded: &'static [EmbeddedOwnedBy<'static>],
+    pub references: &'static [References],
+    /// How long tombstoned documents of a `#[soft_delete]` collection are kept before they
+    /// may be purged, or `None` if the collection has no retention period.
+    pub retention: Option<Duration>,
+}
+
+inventory::collect!(SchemaEntry);
+
+/// Returns the entries of every struct deriving `Schema` that is linked into this binary.
+pub fn entries() -> impl Iterator<Item = &'static SchemaEntry> {
+    inventory::iter::<SchemaEntry>.into_iter()
+}
+
+/// A collection, identified by the `collection_key` of its name. The `Schema` derive
+/// implements `OwnerCollection` on the key of each struct's collection, so that the derive
+/// of an owned struct can check its `#[owned_by]` annotations against the owner's struct
+/// without knowing which struct that is.
+#[doc(hidden)]
+pub struct CollectionKey<const COLLECTION: u64>;
+
+/// Implemented by the key of a collection for the struct deriving `Schema` stored in it.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "no struct deriving `Schema` is stored in the collection named by this annotation",
+    label = "unknown collection"
+)]
+pub trait OwnerCollection<S> {}
+
+/// Implemented by a struct deriving `Schema` for the `collection_key` of the name of each of
+/// its `#[index]` fields, with the type of that field.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "the index named by this annotation is not an `#[index]` field of `{Self}`",
+    label = "not an index of `{Self}`"
+)]
+pub trait OwnerIndex<const INDEX: u64> {
+    type Value;
+}
+
+/// Implemented by the types of fields that can reference owners whose index is a `V`: a
+/// single value, an optional one, or an array of them.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "a field of type `{Self}` cannot reference an index of type `{V}`",
+    label = "does not match the referenced index type"
+)]
+pub trait OwnerReference<V> {}
+
+impl<V> OwnerReference<V> for V {}
+impl<V> OwnerReference<V> for Option<V> {}
+impl<V> OwnerReference<V> for Vec<V> {}
+
+/// Implemented by the types of fields that can be cleared when the document they reference
+/// is deleted: an optional reference, or an array of them. Fields with `on_delete = set_null`
+/// must be, and so must the owned_by fields of structs with several owners.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "a field of type `{Self}` cannot be cleared when the document it references is \
+               deleted",
+    label = "must be an `Option` or a `Vec` of the referenced index type, since the field is \
+             `set_null` or the struct has several owners"
+)]
+pub trait NullableReference<V> {}
+
+impl<V> NullableReference<V> for Option<V> {}
+impl<V> NullableReference<V> for Vec<V> {}
+
+/// Fails to compile unless the collection keyed by `COLLECTION` is stored by a struct
+/// deriving `Schema` with an index field keyed by `INDEX`. The `Schema` derive calls this
+/// for each `#[owned_by]` annotation, passing the type of the index field on to
+/// `check_reference`.
+#[doc(hidden)]
+pub fn owner_of<const COLLECTION: u64, const INDEX: u64, S>(
+) -> PhantomData<<S as OwnerIndex<INDEX>>::Value>
+where
+    CollectionKey<COLLECTION>: OwnerCollection<S>,
+    S: OwnerIndex<INDEX>,
+{
+    PhantomData
+}
+
+/// Fails to compile unless a field of type `R` can reference an index of type `V`.
+#[doc(hidden)]
+pub fn check_reference<V, R: OwnerReference<V>>(_index: PhantomData<V>, _reference: &R) {}
+
+/// Fails to compile unless a field of type `R` can reference an index of type `V` and be
+/// cleared.
+#[doc(hidden)]
+pub fn check_nullable_reference<V, R: NullableReference<V>>(
+    _index: PhantomData<V>,
+    _reference: &R,
+) {
+}
+
+/// Encodes `value` the way the driver does when it stores it as a field of a document, so
+/// that queries match the stored value whatever its type, e.g. a `uuid::Uuid`, which
+/// `bson::to_bson` would encode as a string instead.
+#[doc(hidden)]
+pub fn stored_bson<T: Serialize + ?Sized>(value: &T) -> Result<Bson, Error> {
+    #[derive(Serialize)]
+    struct Stored<'a, T: ?Sized> {
+        value: &'a T,
+    }
+    let document = mongodb::bson::to_raw_document_buf(&Stored { value })
+        .and_then(|raw| {
+            raw.to_document()
+                .map_err(<mongodb::bson::ser::Error as serde::ser::Error>::custom)
+        })
+        .map_err(Error::Encode)?;
+    Ok(document.get("value").cloned().unwrap_or(Bson::Null))
+}
+
+/// The key of `name` used by `CollectionKey` and `OwnerIndex`: its `stable_hash`.
+#[doc(hidden)]
+pub const fn collection_key(name: &str) -> u64 {
+    stable_hash(name.as_bytes())
+}
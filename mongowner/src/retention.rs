@@ -0,0 +1,116 @@
+use crate::delete::DEFAULT_BATCH_SIZE;
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::registry;
+use crate::util::*;
+
+use mongodb::bson::{doc, DateTime};
+use mongodb::Database;
+use petgraph::{graphmap::GraphMap, Directed};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument};
+
+/// Field holding the time at which a soft-deleted document was tombstoned.
+pub const DELETED_AT: &str = "_deleted_at";
+
+/// Field holding the time from which a tombstoned document may be purged: its tombstone time
+/// plus the retention period of the collection of the deletion's subject, so that the whole
+/// owned subtree is kept as long as the subject is.
+pub const PURGE_AFTER: &str = "_purge_after";
+
+/// Hard-deletes every tombstoned document whose `PURGE_AFTER` time has passed, returning how
+/// many were removed. Documents soft-deleted along with a subject without a `#[soft_delete]`
+/// retention period are purged as soon as this runs.
+///
+/// Collections are purged in the order of the ownership graph, owned collections before
+/// their owners, so a purge that fails midway leaves owners behind rather than orphans.
+/// Documents shared with other owners keep their references to tombstoned owners, which
+/// `safe_delete` treats as gone from the moment they are tombstoned: deleting the last live
+/// owner of such a document deletes it, rather than leaving it to reference owners that are
+/// purged later. Entries of `#[embedded_owned_by]` arrays are pulled out along with their
+/// owners.
+pub async fn purge_expired(db: &Database) -> Result<u64, Error> {
+    let graph = load_graph()?;
+    purge_with_graph(&graph, db).await
+}
+
+/// `purge_expired` following `graph`.
+#[instrument(name = "purge_expired", skip_all)]
+pub(crate) async fn purge_with_graph(
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+) -> Result<u64, Error> {
+    let retention: HashMap<&str, Duration> = registry::entries()
+        .filter_map(|entry| Some((entry.collection_name, entry.retention?)))
+        .collect();
+    let sorted = cascade_order(graph)?;
+    let embedded = load_embedded();
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+
+    let started = Instant::now();
+    let now = DateTime::now().timestamp_millis();
+    let mut purged = 0;
+    for collection_name in sorted {
+        let collection_started = Instant::now();
+        let retention_millis = retention
+            .get(collection_name)
+            .map_or(0, |retention| retention.as_millis() as i64);
+        let expired_before = DateTime::from_millis(now.saturating_sub(retention_millis));
+        // Tombstones from before `PURGE_AFTER` was recorded expire with their own collection
+        let expired = doc! { "$or": [
+            { PURGE_AFTER: { "$lte": DateTime::from_millis(now) } },
+            { PURGE_AFTER: { "$exists": false }, DELETED_AT: { "$lte": expired_before } },
+        ] };
+
+        for (embedding_coll, embedded) in embedded
+            .iter()
+            .filter(|(_, embedded)| embedded.owner_collection == collection_name)
+        {
+            let owners = executor
+                .find(
+                    collection_name,
+                    expired.clone(),
+                    Some(doc! { embedded.owner_index: 1 }),
+                )
+                .await?;
+            let values = owners
+                .iter()
+                .filter_map(|owner| owner.get(embedded.owner_index).cloned())
+                .collect();
+            executor
+                .pull_in(
+                    embedding_coll,
+                    embedded.array_field,
+                    embedded.element_field,
+                    values,
+                )
+                .await?;
+        }
+
+        let deleted = executor.delete_many(collection_name, expired).await?;
+        debug!(
+            collection = collection_name,
+            deleted,
+            elapsed_ms = elapsed_ms(collection_started),
+            "purged expired documents"
+        );
+        purged += deleted;
+    }
+
+    info!(
+        purged,
+        elapsed_ms = elapsed_ms(started),
+        "purged expired documents"
+    );
+    Ok(purged)
+}
+
+/// The retention period of `collection_name`, or none if it has no `#[soft_delete]`
+/// annotation.
+pub(crate) fn retention_of(collection_name: &str) -> Duration {
+    registry::entries()
+        .find(|entry| entry.collection_name == collection_name)
+        .and_then(|entry| entry.retention)
+        .unwrap_or_default()
+}
@@ -1,8 +1,15 @@
-use dotenv::dotenv;
+use crate::error::Error;
+use crate::registry::{self, EmbeddedOwnedBy, OnDelete};
+
+use petgraph::algo::toposort;
+use petgraph::visit::EdgeFiltered;
 use petgraph::{graphmap::GraphMap, Directed};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
-use std::{env, fmt::Debug, fs, io::Read};
+use std::time::Instant;
+use std::{fmt::Debug, fs, io::Read};
+use tracing::{debug, trace};
 
 /// Represents an edge between two structs.
 /// Ex. for User, Post, we would have owner_index = user_id, owned_field = posted_by
@@ -10,27 +17,174 @@ use std::{env, fmt::Debug, fs, io::Read};
 pub struct OwnEdge<'a> {
     pub owner_index: &'a str,
     pub owned_field: &'a str,
+    #[serde(default)]
+    pub kind: EdgeKind,
+}
+
+/// The relation an edge of the ownership graph stands for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EdgeKind {
+    /// An `#[owned_by]` annotation: the owned document is deleted along with its last owner.
+    #[default]
+    Owned,
+    /// A `#[references]` annotation, with what happens to the referencing document when the
+    /// referenced one is deleted.
+    References(OnDelete),
+}
+
+impl EdgeKind {
+    /// Whether deleting the target of an edge of this kind may delete its source, so that
+    /// the edge takes part in the order of a cascade.
+    pub fn cascades(&self) -> bool {
+        matches!(
+            self,
+            EdgeKind::Owned | EdgeKind::References(OnDelete::Cascade)
+        )
+    }
+}
+
+/// Returns the ownership graph of every struct deriving `Schema` in this binary. The graph
+/// is built from the entries the derive registers at compile time, so it needs no files.
+/// Fails if the ownership relations form a cycle.
+pub fn load_graph() -> Result<GraphMap<&'static str, OwnEdge<'static>, Directed>, Error> {
+    let mut graph = GraphMap::new();
+    for entry in registry::entries() {
+        graph.add_node(entry.collection_name);
+        for owned_by in entry.owned_by {
+            graph.add_edge(
+                entry.collection_name,
+                owned_by.owner_collection,
+                OwnEdge {
+                    owner_index: owned_by.owner_index,
+                    owned_field: owned_by.owned_field,
+                    kind: EdgeKind::Owned,
+                },
+            );
+        }
+        for references in entry.references {
+            graph.add_edge(
+                entry.collection_name,
+                references.referenced_collection,
+                OwnEdge {
+                    owner_index: references.referenced_index,
+                    owned_field: references.referencing_field,
+                    kind: EdgeKind::References(references.on_delete),
+                },
+            );
+        }
+    }
+    debug!(
+        collections = graph.node_count(),
+        edges = graph.edge_count(),
+        "loaded ownership graph"
+    );
+    trace!(?graph, "ownership graph");
+    cascade_order(&graph)?;
+    Ok(graph)
+}
+
+/// Milliseconds since `started`, as recorded by the duration fields of mongowner's events.
+pub(crate) fn elapsed_ms(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+/// A fingerprint of `graph`, which is the same for graphs with the same collections and
+/// edges, whatever order they were registered in. Receipts record it so that a deletion can
+/// be matched to the ownership rules it followed.
+pub fn graph_version(graph: &GraphMap<&str, OwnEdge<'_>, Directed>) -> String {
+    let mut lines: Vec<String> = graph.nodes().map(|node| node.to_string()).collect();
+    lines.extend(graph.all_edges().map(|(from, to, edge)| {
+        format!(
+            "{}.{} -> {}.{} {:?}",
+            from, edge.owned_field, to, edge.owner_index, edge.kind
+        )
+    }));
+    lines.sort();
+    format!("{:016x}", stable_hash(lines.join("\n").as_bytes()))
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike the std hashers, it is fixed by its definition
+/// rather than by the Rust version or a random seed, so it is the same in every build and
+/// on every platform, and can be computed at compile time. Graph versions are persisted in
+/// receipts and collection keys are baked into the binary, so both rely on that.
+pub(crate) const fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
 }
 
-/// Accepts a mutable string buffer and returns the graph stored in the path {OUT_DIR}/graph.json.
-pub fn load_graph<'a>(
+/// Sorts the collections of `graph` so that every collection comes before the collections
+/// whose deletion can cascade to it, e.g. owned collections before their owners. Only the
+/// edges that cascade must be acyclic; other references may point anywhere.
+pub fn cascade_order<'g>(
+    graph: &GraphMap<&'g str, OwnEdge<'g>, Directed>,
+) -> Result<Vec<&'g str>, Error> {
+    let cascading =
+        EdgeFiltered::from_fn(graph, |(_, _, edge): (_, _, &OwnEdge)| edge.kind.cascades());
+    toposort(&cascading, None).map_err(|cycle| Error::Cycle {
+        collection: cycle.node_id().to_string(),
+    })
+}
+
+/// Returns the map from collection names to the names of their index fields for every
+/// struct deriving `Schema` in this binary.
+pub fn load_index_map() -> HashMap<&'static str, &'static [&'static str]> {
+    registry::entries()
+        .map(|entry| (entry.collection_name, entry.index_names))
+        .collect()
+}
+
+/// Returns every `#[embedded_owned_by]` annotation of the structs deriving `Schema` in this
+/// binary, along with the collection holding the annotated arrays.
+pub fn load_embedded() -> Vec<(&'static str, EmbeddedOwnedBy<'static>)> {
+    registry::entries()
+        .flat_map(|entry| {
+            entry
+                .embedded
+                .iter()
+                .map(|embedded| (entry.collection_name, *embedded))
+        })
+        .collect()
+}
+
+/// Accepts a mutable string buffer and returns the graph stored at `path`, such as the
+/// target/graph.json written by the `Schema` derive. This is meant for tooling that works
+/// on the graph of another binary; applications should use `load_graph`.
+pub fn load_graph_file<'a>(
+    path: &Path,
     contents: &'a mut String,
-) -> Result<GraphMap<&'_ str, OwnEdge<'_>, Directed>, Box<dyn std::error::Error>> {
-    dotenv().ok();
-    // Reference the graph in env::var("CARGO_MANIFEST_DIR")
-    let out_dir =
-        env::var("CARGO_MANIFEST_DIR").expect("Error reading CARGO_MANIFEST_DIR env variable");
-    let dir_path = Path::new(&out_dir);
-    let graph_path = dir_path
-        .join("target")
-        .join(std::env::var("GRAPH_NAME").unwrap_or("graph.json".to_string()));
-    println!("DEBUG safe_delete: Loading graph from {:#?}", graph_path);
-    let mut file = fs::File::open(graph_path)?;
-    file.read_to_string(contents)?;
-    let graph: GraphMap<&str, OwnEdge, Directed> = match serde_json::from_str(contents) {
-        Ok(g) => g,
-        Err(_) => GraphMap::new(),
+) -> Result<GraphMap<&'a str, OwnEdge<'a>, Directed>, Error> {
+    read_graph_file(path, contents)
+}
+
+/// Reads the JSON file at `path`, one of the files written by the `Schema` derive, into
+/// `contents` and parses it.
+fn read_graph_file<'a, T: Deserialize<'a>>(
+    path: &Path,
+    contents: &'a mut String,
+) -> Result<T, Error> {
+    let graph_load = |source: Box<dyn std::error::Error + Send + Sync>| Error::GraphLoad {
+        path: path.to_path_buf(),
+        source,
     };
-    println!("DEBUG safe_delete: {:#?}", graph);
-    Ok(graph)
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(contents))
+        .map_err(|e| graph_load(e.into()))?;
+    serde_json::from_str(contents).map_err(|e| graph_load(e.into()))
+}
+
+/// Accepts a mutable string buffer and returns the `#[embedded_owned_by]` annotations stored
+/// at `path`, such as the target/embedded.json written by the `Schema` derive next to
+/// graph.json, along with the collection holding the annotated arrays. Like
+/// `load_graph_file`, this is meant for tooling; applications should use `load_embedded`.
+pub fn load_embedded_file<'a>(
+    path: &Path,
+    contents: &'a mut String,
+) -> Result<Vec<(&'a str, EmbeddedOwnedBy<'a>)>, Error> {
+    read_graph_file(path, contents)
 }
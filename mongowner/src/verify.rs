@@ -0,0 +1,158 @@
+use crate::cascade::{find_roots, reachable_in_order};
+use crate::delete::{Schemable, DEFAULT_BATCH_SIZE};
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::util::*;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::Database;
+use petgraph::{graphmap::GraphMap, Directed, Direction};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// What is left of a deleted data subject, as found by `verify_deleted`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Verification {
+    /// The `_id`s of the documents left behind, by the name of their collection: the subject
+    /// itself if it still exists, and the documents that still reference it, directly or
+    /// through other documents left behind, by ownership, `#[references]` or embedded
+    /// entries.
+    pub remaining: BTreeMap<String, Vec<Bson>>,
+}
+
+impl Verification {
+    /// Whether nothing was left behind.
+    pub fn is_clean(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// Total number of documents left behind across all collections.
+    pub fn total_count(&self) -> usize {
+        self.remaining.values().map(Vec::len).sum()
+    }
+}
+
+/// Checks that the data subject of type `T` whose index is `index_value` was deleted: walks
+/// the ownership graph from it the way `safe_delete` does, and reports every document that
+/// still exists or still references it.
+pub async fn verify_deleted<T: Schemable>(
+    index_value: T::Value,
+    db: &Database,
+) -> Result<Verification, Error> {
+    let graph = load_graph()?;
+    verify_with_graph::<T>(index_value, &graph, db).await
+}
+
+/// `verify_deleted` following `graph`.
+pub(crate) async fn verify_with_graph<T: Schemable>(
+    index_value: T::Value,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    db: &Database,
+) -> Result<Verification, Error> {
+    let mut executor = Executor::new(db, DEFAULT_BATCH_SIZE);
+    verify_subject(
+        T::collection_name(),
+        vec![T::index_filter_for(&index_value)?],
+        graph,
+        &mut executor,
+    )
+    .await
+}
+
+/// Finds what is left of the documents of `root_coll` matching any of `root_filters`, using
+/// the queries of `executor`, so that a transactional deletion can be verified before it is
+/// committed.
+pub(crate) async fn verify_subject(
+    root_coll: &str,
+    root_filters: Vec<Document>,
+    graph: &GraphMap<&str, OwnEdge<'_>, Directed>,
+    executor: &mut Executor<'_>,
+) -> Result<Verification, Error> {
+    let mut remaining: HashMap<&str, Vec<Document>> = HashMap::new();
+    let roots = find_roots(root_coll, &root_filters, executor).await?;
+    remaining.insert(root_coll, roots);
+    // What documents may still reference: the subject, whether or not it exists, and
+    // everything found left behind
+    let referenced = |remaining: &HashMap<&str, Vec<Document>>, collection_name: &str| {
+        let mut documents: Vec<Document> =
+            remaining.get(collection_name).cloned().unwrap_or_default();
+        if collection_name == root_coll {
+            documents.extend(root_filters.iter().cloned());
+        }
+        documents
+    };
+
+    // Collections the deletion cascades to, owners first, then the ones that only hold
+    // references to deleted documents
+    let mut collections: Vec<&str> = reachable_in_order(root_coll, graph)?
+        .into_iter()
+        .skip(1)
+        .map(|(collection_name, _)| collection_name)
+        .collect();
+    for (collection_name, _, edge) in graph.all_edges() {
+        if !edge.kind.cascades() && !collections.contains(&collection_name) {
+            collections.push(collection_name);
+        }
+    }
+
+    for collection_name in collections {
+        let mut found = Vec::new();
+        let mut ids = HashSet::new();
+        for (_, referenced_coll, edge) in graph.edges_directed(collection_name, Direction::Outgoing)
+        {
+            let values: Vec<Bson> = referenced(&remaining, referenced_coll)
+                .iter()
+                .filter_map(|document| document.get(edge.owner_index).cloned())
+                .collect();
+            let documents = executor
+                .find_in(
+                    collection_name,
+                    edge.owned_field,
+                    values,
+                    Document::new(),
+                    None,
+                )
+                .await?;
+            found.extend(
+                documents
+                    .into_iter()
+                    .filter(|document| ids.insert(document.get("_id").map(Bson::to_string))),
+            );
+        }
+        remaining.entry(collection_name).or_default().extend(found);
+    }
+
+    for (collection_name, embedded) in load_embedded() {
+        let values: Vec<Bson> = referenced(&remaining, embedded.owner_collection)
+            .iter()
+            .filter_map(|document| document.get(embedded.owner_index).cloned())
+            .collect();
+        let field = match embedded.element_field {
+            Some(element_field) => format!("{}.{}", embedded.array_field, element_field),
+            None => embedded.array_field.to_string(),
+        };
+        let documents = executor
+            .find_in(collection_name, &field, values, Document::new(), None)
+            .await?;
+        remaining
+            .entry(collection_name)
+            .or_default()
+            .extend(documents);
+    }
+
+    let mut verification = Verification::default();
+    for (collection_name, documents) in remaining {
+        let mut ids = HashSet::new();
+        let remaining_ids: Vec<Bson> = documents
+            .iter()
+            .filter_map(|document| document.get("_id").cloned())
+            .filter(|id| ids.insert(id.to_string()))
+            .collect();
+        if !remaining_ids.is_empty() {
+            verification
+                .remaining
+                .insert(collection_name.to_string(), remaining_ids);
+        }
+    }
+    Ok(verification)
+}
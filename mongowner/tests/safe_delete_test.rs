@@ -1,15 +1,38 @@
-use mongowner::delete::safe_delete;
+use mongowner::audit::DeletionReceipt;
+use mongowner::delete::{
+    safe_delete, safe_delete_by_id, safe_delete_by_ids, safe_delete_matching,
+    safe_delete_matching_with_options, safe_delete_transaction,
+    safe_delete_transaction_with_options, safe_delete_with_options, DeleteMode, DeleteOptions,
+};
+use mongowner::export::export_subject;
+use mongowner::jobs::{
+    enqueue_delete, find_job, run_next_job, run_pending_jobs, JobStatus, WorkerOptions,
+    JOBS_COLLECTION,
+};
+use mongowner::orphans::{delete_orphans, delete_orphans_with_graph, find_orphans};
+use mongowner::plan::plan_delete;
+use mongowner::progress::{safe_delete_with_progress, Cancellation, DeleteEvent};
+use mongowner::registry::{EmbeddedOwnedBy, OnDelete};
+use mongowner::retention::{purge_expired, DELETED_AT, PURGE_AFTER};
+use mongowner::util::{
+    graph_version, load_embedded, load_embedded_file, load_graph, EdgeKind, OwnEdge,
+};
+use mongowner::verify::verify_deleted;
+use std::collections::BTreeMap;
 use std::ops::Range;
-use std::u32;
+use std::path::Path;
 
 use fake::faker::boolean::en::Boolean;
 use fake::faker::internet::en::{FreeEmail, Username};
 use fake::faker::lorem::en::{Paragraph, Word};
 use fake::faker::name::en::Name;
 use fake::Fake;
+use futures::StreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document, Uuid};
+use mongodb::options::CreateCollectionOptions;
 use mongodb::{Client, Collection, Database};
-use mongowner::{Schema, Schemable};
-use rand::random;
+use mongowner::{Error, Mongowner, Schema, Schemable, SchemableExt};
+use petgraph::graphmap::GraphMap;
 use serde::{Deserialize, Serialize};
 
 /// A set of schemas meant to encompass different ownership relations and structures
@@ -53,9 +76,9 @@ pub struct Comment {
     #[index]
     id: u32,
     #[owned_by(users, id)]
-    commented_by: u32,
+    commented_by: Option<u32>,
     #[owned_by(posts, id)]
-    parent_post: u32,
+    parent_post: Option<u32>,
     text: String,
     date: String,
 }
@@ -66,9 +89,9 @@ pub struct Productive {
     #[index]
     id: u32,
     #[owned_by(users, id)]
-    rated_by: u32,
+    rated_by: Option<u32>,
     #[owned_by(comments, id)]
-    comment: u32,
+    comment: Option<u32>,
     is_productive: bool,
 }
 
@@ -76,11 +99,11 @@ pub struct Productive {
 #[collection(attachments)]
 pub struct Attachment {
     #[owned_by(users, id)]
-    user: u32,
+    user: Option<u32>,
     #[owned_by(posts, id)]
-    post: u32,
+    post: Option<u32>,
     #[owned_by(comments, id)]
-    comment: u32,
+    comment: Option<u32>,
     #[index]
     resource_id: u32,
     date: String,
@@ -144,10 +167,159 @@ pub struct MResource3 {
     #[owned_by(modresources, id)]
     parent_res: u32,
 }
-pub async fn init_test_db() -> Result<Database, String> {
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(accounts)]
+#[soft_delete(retention_days = 30)]
+pub struct Account {
+    #[index]
+    id: u32,
+    email: String,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(invoices)]
+pub struct Invoice {
+    #[index]
+    id: u32,
+    amount: u32,
+    #[owned_by(accounts, id)]
+    billed_to: u32,
+}
+
+// A schema whose fields are stored under the names serde gives them
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(reviews)]
+#[serde(rename_all = "camelCase")]
+pub struct Review {
+    #[index]
+    review_id: u32,
+    #[owned_by(users, id)]
+    #[serde(rename = "author")]
+    written_by: Option<u32>,
+    #[owned_by(posts, id)]
+    reviewed_post: Option<u32>,
+}
+
+// A chat shared by all of its members, and a note whose author is nested in its metadata
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(chats)]
+pub struct Chat {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    members: Vec<u32>,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(notes)]
+pub struct Note {
+    #[index]
+    id: u32,
+    #[owned_by(users, id, path = "author_id")]
+    meta: NoteMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NoteMeta {
+    author_id: u32,
+    pinned: bool,
+}
+
+// A photo with reactions and tags of other users embedded in it
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(photos)]
+pub struct Photo {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    posted_by: u32,
+    #[embedded_owned_by(users, id, path = "user_id")]
+    reactions: Vec<Reaction>,
+    #[embedded_owned_by(users, id)]
+    tagged: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Reaction {
+    user_id: u32,
+    emoji: String,
+}
+
+// References to documents that aren't owned by them, one for each on_delete action
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(bookmarks)]
+pub struct Bookmark {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    saved_by: u32,
+    #[references(posts, id, on_delete = cascade)]
+    post_id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(highlights)]
+pub struct Highlight {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    highlighted_by: u32,
+    #[references(comments, id, on_delete = set_null)]
+    comment_id: Option<u32>,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(flags)]
+pub struct Flag {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    flagged_by: u32,
+    #[references(posts, id)]
+    post_id: u32,
+}
+
+// Schemas indexed by Mongo's own ObjectId `_id`, a Uuid and a composite index
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(members)]
+pub struct Member {
+    #[index]
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    name: String,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(sessions)]
+pub struct Session {
+    #[index]
+    token: Uuid,
+    #[owned_by(members, _id)]
+    member: ObjectId,
+}
+
+#[derive(Schema, Serialize, Deserialize, Clone)]
+#[collection(memberships)]
+pub struct Membership {
+    #[index]
+    org: String,
+    #[index]
+    seat: u32,
+    #[owned_by(members, _id)]
+    member: ObjectId,
+}
+
+pub async fn init_test_client() -> Client {
     let uri = "mongodb://localhost:27017";
-    let client = Client::with_uri_str(uri).await.expect("failed to connect");
-    let db_name = format!("test_db{}", random::<u8>());
+    Client::with_uri_str(uri).await.expect("failed to connect")
+}
+
+pub async fn init_test_db() -> Result<Database, String> {
+    let client = init_test_client().await;
+    let db_name = format!("test_db_{}", ObjectId::new());
     let db = client.database(&db_name);
     teardown_db(&db).await;
     Ok(db)
@@ -174,6 +346,27 @@ pub async fn insert_posts(coll: &Collection<Post>, owner_id: u32, count: u32) {
         .expect("Error generating posts");
 }
 
+/// Inserts Photo0 and Photo1, each posted by the user of the same id, with reactions and
+/// tags of User0 and User1
+pub async fn insert_photos(coll: &Collection<Photo>) {
+    let photos: Vec<Photo> = (0..2)
+        .map(|n| Photo {
+            id: n,
+            posted_by: n,
+            reactions: (0..2)
+                .map(|user_id| Reaction {
+                    user_id,
+                    emoji: Word().fake(),
+                })
+                .collect(),
+            tagged: vec![0, 1],
+        })
+        .collect();
+    coll.insert_many(photos, None)
+        .await
+        .expect("Failed to insert photos");
+}
+
 pub async fn insert_user(coll: &Collection<User>, user_id: u32) -> User {
     let user = User {
         id: user_id,
@@ -197,8 +390,8 @@ pub async fn insert_comments(
     for n in 0..count {
         comments.push(Comment {
             id: n,
-            parent_post: commented_on,
-            commented_by,
+            parent_post: Some(commented_on),
+            commented_by: Some(commented_by),
             text: Word().fake(),
             date: "Dec 14, 2023".to_string(),
         });
@@ -219,8 +412,8 @@ pub async fn insert_productives(
     for n in 0..count {
         productives.push(Productive {
             id: n,
-            rated_by,
-            comment,
+            rated_by: Some(rated_by),
+            comment: Some(comment),
             is_productive: Boolean(1).fake(),
         });
     }
@@ -239,9 +432,9 @@ pub async fn insert_attachments(
     let mut v: Vec<Attachment> = Vec::new();
     for n in 0..count {
         v.push(Attachment {
-            user,
-            post,
-            comment,
+            user: Some(user),
+            post: Some(post),
+            comment: Some(comment),
             resource_id: n,
             date: "Dec 14, 2023".to_string(),
             moderated_by: 0,
@@ -334,6 +527,20 @@ pub async fn insert_mresources3(coll: &Collection<MResource3>, count: Range<u32>
         .expect("Error generating mresources3");
 }
 
+pub async fn insert_invoices(coll: &Collection<Invoice>, account_id: u32, count: u32) {
+    let mut invoices: Vec<Invoice> = Vec::new();
+    for n in 0..count {
+        invoices.push(Invoice {
+            id: n,
+            amount: (1..1000).fake(),
+            billed_to: account_id,
+        });
+    }
+    coll.insert_many(invoices, None)
+        .await
+        .expect("Failed to insert invoices");
+}
+
 pub async fn coll_count<T>(coll: &Collection<T>) -> u64 {
     coll.estimated_document_count(None)
         .await
@@ -345,6 +552,442 @@ pub fn set_graph_name() {
     // std::env::set_var("GRAPH_NAME", &graph_name);
     // println!("TEST DEUBG: GRAPH_NAME is {}", graph_name);
 }
+// The ownership graph is built from the Schema derives compiled into this binary
+#[test]
+fn load_graph_from_registry() {
+    let graph = load_graph().expect("Error loading graph");
+
+    let edge = graph
+        .edge_weight(Comment::collection_name(), Post::collection_name())
+        .expect("Comment should be owned by Post");
+    assert_eq!("id", edge.owner_index);
+    assert_eq!("parent_post", edge.owned_field);
+    assert!(graph.contains_edge(Post::collection_name(), User::collection_name()));
+    assert!(!graph.contains_edge(User::collection_name(), Post::collection_name()));
+    assert!(graph.contains_node(MediaMod::collection_name()));
+}
+
+// The retention period of #[soft_delete] collections is registered along with the graph
+#[test]
+fn soft_delete_retention_from_registry() {
+    let retention = |collection_name: &str| {
+        mongowner::registry::entries()
+            .find(|entry| entry.collection_name == collection_name)
+            .expect("Schema should be registered")
+            .retention
+    };
+    assert_eq!(
+        Some(std::time::Duration::from_secs(30 * 24 * 60 * 60)),
+        retention(Account::collection_name())
+    );
+    assert_eq!(None, retention(Invoice::collection_name()));
+}
+
+// Ownership queries use the field names serde stores documents under
+#[test]
+fn serde_renames_in_registry() {
+    let review = mongowner::registry::entries()
+        .find(|entry| entry.collection_name == Review::collection_name())
+        .expect("Review should be registered");
+    assert_eq!(&["reviewId"], review.index_names);
+    let owned_fields: Vec<&str> = review
+        .owned_by
+        .iter()
+        .map(|owned_by| owned_by.owned_field)
+        .collect();
+    assert_eq!(vec!["author", "reviewedPost"], owned_fields);
+}
+
+#[tokio::test]
+async fn safe_delete_renamed_fields() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let review_coll = db.collection::<Review>(Review::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 1).await;
+    let reviews: Vec<Review> = (0..10)
+        .map(|n| Review {
+            review_id: n,
+            written_by: Some(0),
+            reviewed_post: Some(0),
+        })
+        .collect();
+    review_coll
+        .insert_many(reviews, None)
+        .await
+        .expect("Failed to insert reviews");
+
+    safe_delete(user, &db).await.expect("Error safe deleting");
+
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(0, coll_count(&review_coll).await);
+    teardown_db(&db).await;
+}
+
+#[test]
+fn nested_owned_by_path_in_registry() {
+    let graph = load_graph().expect("Error loading graph");
+    let edge = graph
+        .edge_weight(Note::collection_name(), User::collection_name())
+        .expect("Note should be owned by User");
+    assert_eq!("meta.author_id", edge.owned_field);
+}
+
+// Array references are shared by every owner in them, and nested references are followed
+#[tokio::test]
+async fn safe_delete_array_and_nested_references() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let chat_coll = db.collection::<Chat>(Chat::collection_name());
+    let note_coll = db.collection::<Note>(Note::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    let chats = vec![
+        Chat {
+            id: 0,
+            members: vec![0, 1],
+        },
+        Chat {
+            id: 1,
+            members: vec![0],
+        },
+    ];
+    chat_coll
+        .insert_many(chats, None)
+        .await
+        .expect("Failed to insert chats");
+    let notes: Vec<Note> = (0..4)
+        .map(|n| Note {
+            id: n,
+            meta: NoteMeta {
+                author_id: n % 2,
+                pinned: Boolean(50).fake(),
+            },
+        })
+        .collect();
+    note_coll
+        .insert_many(notes, None)
+        .await
+        .expect("Failed to insert notes");
+
+    safe_delete(user, &db).await.expect("Error safe deleting");
+
+    // Chat0 is still owned by User1, Chat1 had no other member
+    let remaining = chat_coll
+        .find_one(doc! {}, None)
+        .await
+        .unwrap()
+        .expect("Chat0 should remain");
+    assert_eq!((0, vec![1]), (remaining.id, remaining.members));
+    assert_eq!(1, coll_count(&chat_coll).await);
+    assert_eq!(2, coll_count(&note_coll).await);
+    teardown_db(&db).await;
+}
+
+#[test]
+fn embedded_owned_by_in_registry() {
+    let embedded = load_embedded();
+    let photo_embedded: Vec<_> = embedded
+        .iter()
+        .filter(|(collection_name, _)| *collection_name == Photo::collection_name())
+        .map(|(_, embedded)| {
+            (
+                embedded.owner_collection,
+                embedded.array_field,
+                embedded.element_field,
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            ("users", "reactions", Some("user_id")),
+            ("users", "tagged", None)
+        ],
+        photo_embedded
+    );
+    // Embedded entries don't make their documents owned by anyone else
+    let graph = load_graph().expect("Error loading graph");
+    assert_eq!(1, graph.neighbors(Photo::collection_name()).count());
+}
+
+// Embedded entries of a deleted user are pulled out of documents it doesn't own
+#[tokio::test]
+async fn safe_delete_embedded_entries() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let photo_coll = db.collection::<Photo>(Photo::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_photos(&photo_coll).await;
+
+    safe_delete(user, &db).await.expect("Error safe deleting");
+
+    // Photo0 is deleted with its owner, Photo1 only loses User0's reactions and tags
+    assert_eq!(1, coll_count(&photo_coll).await);
+    let remaining = photo_coll
+        .find_one(doc! {}, None)
+        .await
+        .unwrap()
+        .expect("Photo1 should remain");
+    assert_eq!(1, remaining.id);
+    let reactors: Vec<u32> = remaining.reactions.iter().map(|r| r.user_id).collect();
+    assert_eq!(vec![1], reactors);
+    assert_eq!(vec![1], remaining.tagged);
+    teardown_db(&db).await;
+}
+
+// Planning and exporting report the embedded entries of a user but not the rest of the
+// documents holding them
+#[tokio::test]
+async fn plan_and_export_embedded_entries() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let photo_coll = db.collection::<Photo>(Photo::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_photos(&photo_coll).await;
+
+    let plan = plan_delete(&user, &db)
+        .await
+        .expect("Error planning delete");
+    let photos = &plan.collections[Photo::collection_name()];
+    assert_eq!(vec![Bson::Int64(0)], photos.index_values);
+    // Photo0 is deleted with its owner, so only Photo1 has entries pulled
+    for array_field in ["reactions", "tagged"] {
+        let pulled = &plan.embedded[&format!("{}.{}", Photo::collection_name(), array_field)];
+        assert_eq!(vec![Bson::Int64(1)], pulled.index_values);
+        assert_eq!(vec![Bson::Int64(0)], pulled.owner_values);
+    }
+
+    let export = export_subject(&user, &db)
+        .await
+        .expect("Error exporting subject");
+    let reactions = &export.collections[&format!("{}.reactions", Photo::collection_name())];
+    assert_eq!(2, reactions.len());
+    for document in reactions {
+        let entries = document.get_array("reactions").unwrap();
+        assert_eq!(1, entries.len());
+        let entry = entries[0].as_document().unwrap();
+        assert_eq!(0, entry.get_i64("user_id").unwrap());
+    }
+    let tagged = &export.collections[&format!("{}.tagged", Photo::collection_name())];
+    assert!(tagged
+        .iter()
+        .all(|document| document.get_array("tagged").unwrap() == &vec![Bson::Int64(0)]));
+    teardown_db(&db).await;
+}
+
+#[test]
+fn references_in_graph() {
+    let graph = load_graph().expect("Error loading graph");
+    let kind = |from: &str, to: &str| graph.edge_weight(from, to).map(|edge| edge.kind);
+    assert_eq!(
+        Some(EdgeKind::References(OnDelete::Cascade)),
+        kind(Bookmark::collection_name(), Post::collection_name())
+    );
+    assert_eq!(
+        Some(EdgeKind::References(OnDelete::SetNull)),
+        kind(Highlight::collection_name(), Comment::collection_name())
+    );
+    assert_eq!(
+        Some(EdgeKind::References(OnDelete::Restrict)),
+        kind(Flag::collection_name(), Post::collection_name())
+    );
+    assert_eq!(
+        Some(EdgeKind::Owned),
+        kind(Flag::collection_name(), User::collection_name())
+    );
+}
+
+// UserB's bookmark of UserA's post goes with the post, and UserB's highlight of UserA's
+// comment loses its reference
+#[tokio::test]
+async fn safe_delete_references_cascade_and_set_null() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let bookmark_coll = db.collection::<Bookmark>(Bookmark::collection_name());
+    let highlight_coll = db.collection::<Highlight>(Highlight::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_posts(&post_coll, 0, 2).await;
+    insert_comments(&comment_coll, 0, 0, 2).await;
+    let bookmark = Bookmark {
+        id: 0,
+        saved_by: 1,
+        post_id: 0,
+    };
+    bookmark_coll
+        .insert_one(bookmark, None)
+        .await
+        .expect("Failed to insert bookmark");
+    let highlight = Highlight {
+        id: 0,
+        highlighted_by: 1,
+        comment_id: Some(0),
+    };
+    highlight_coll
+        .insert_one(highlight, None)
+        .await
+        .expect("Failed to insert highlight");
+
+    safe_delete(user, &db).await.expect("Error safe deleting");
+
+    assert_eq!(1, coll_count(&user_coll).await);
+    assert_eq!(0, coll_count(&bookmark_coll).await);
+    let highlight = highlight_coll
+        .find_one(doc! {}, None)
+        .await
+        .unwrap()
+        .expect("Highlight should remain");
+    assert_eq!(None, highlight.comment_id);
+    teardown_db(&db).await;
+}
+
+// Planning the deletion of a post reports the highlight of one of its comments, although
+// highlights are owned by users, so out of the post's reach
+#[tokio::test]
+async fn plan_delete_reports_unreached_set_null() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let highlight_coll = db.collection::<Highlight>(Highlight::collection_name());
+    insert_posts(&post_coll, 0, 2).await;
+    insert_comments(&comment_coll, 0, 0, 2).await;
+    let highlight = Highlight {
+        id: 0,
+        highlighted_by: 1,
+        comment_id: Some(0),
+    };
+    highlight_coll
+        .insert_one(highlight, None)
+        .await
+        .expect("Failed to insert highlight");
+    let post = post_coll
+        .find_one(doc! { "id": 0 }, None)
+        .await
+        .unwrap()
+        .expect("Post0 should exist");
+
+    let plan = plan_delete(&post, &db)
+        .await
+        .expect("Error planning delete");
+
+    assert_eq!(2, plan.collections[Comment::collection_name()].count());
+    let highlights = &plan.collections[Highlight::collection_name()];
+    assert_eq!(2, highlights.depth);
+    assert_eq!(0, highlights.count());
+    assert_eq!(vec![Bson::Int64(0)], highlights.unlinked_values);
+    teardown_db(&db).await;
+}
+
+// UserB's flag on UserA's post prevents deleting UserA, but UserA's own flags don't
+#[tokio::test]
+async fn safe_delete_references_restrict() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let flag_coll = db.collection::<Flag>(Flag::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_posts(&post_coll, 0, 2).await;
+    let flags: Vec<Flag> = (0..2)
+        .map(|n| Flag {
+            id: n,
+            flagged_by: n,
+            post_id: n,
+        })
+        .collect();
+    flag_coll
+        .insert_many(flags, None)
+        .await
+        .expect("Failed to insert flags");
+
+    let result = safe_delete(user, &db).await;
+    assert!(matches!(result, Err(Error::Restricted { .. })));
+    assert_eq!(2, coll_count(&user_coll).await);
+    assert_eq!(2, coll_count(&post_coll).await);
+    assert_eq!(2, coll_count(&flag_coll).await);
+
+    flag_coll
+        .delete_one(doc! { "flagged_by": 1 }, None)
+        .await
+        .expect("Failed to delete flag");
+    let user = user_coll
+        .find_one(doc! { "id": 0 }, None)
+        .await
+        .unwrap()
+        .expect("User0 should remain");
+    safe_delete(user, &db).await.expect("Error safe deleting");
+    assert_eq!(1, coll_count(&user_coll).await);
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(0, coll_count(&flag_coll).await);
+    teardown_db(&db).await;
+}
+
+#[test]
+fn graph_version_ignores_registration_order() {
+    let edges = [
+        ("posts", "users", "posted_by", "id"),
+        ("comments", "posts", "parent_post", "id"),
+        ("comments", "users", "commented_by", "id"),
+    ];
+    let mut graph = GraphMap::new();
+    let mut reversed = GraphMap::new();
+    for (target, list) in [
+        (&mut graph, edges.to_vec()),
+        (&mut reversed, edges.iter().rev().cloned().collect()),
+    ] {
+        for (owned, owner, owned_field, owner_index) in list {
+            target.add_edge(
+                owned,
+                owner,
+                OwnEdge {
+                    owner_index,
+                    owned_field,
+                    kind: EdgeKind::Owned,
+                },
+            );
+        }
+    }
+    assert_eq!(graph_version(&graph), graph_version(&reversed));
+    graph.remove_edge("comments", "users");
+    assert_ne!(graph_version(&graph), graph_version(&reversed));
+}
+
+// Receipts keep graph versions, so they mustn't change between builds
+#[test]
+fn graph_version_is_stable() {
+    let mut graph = GraphMap::new();
+    assert_eq!("cbf29ce484222325", graph_version(&graph));
+    graph.add_node("users");
+    assert_eq!("42288471628c3333", graph_version(&graph));
+}
+
+// Index filters hold the index values as the driver stores them
+#[test]
+fn index_filter_matches_stored_document() {
+    let session = Session {
+        token: Uuid::new(),
+        member: ObjectId::new(),
+    };
+    let stored = mongodb::bson::to_raw_document_buf(&session)
+        .unwrap()
+        .to_document()
+        .unwrap();
+    let filter = session.index_filter().expect("Error encoding index");
+    assert_eq!(doc! { "token": stored.get("token").unwrap() }, filter);
+
+    let member = Member {
+        id: ObjectId::new(),
+        name: Name().fake(),
+    };
+    assert_eq!(vec!["_id"], Member::index_names());
+    assert_eq!(doc! { "_id": member.id }, member.index_filter().unwrap());
+}
+
 // tests if safe_delete works when 1 user owns 1 post
 #[tokio::test]
 async fn safe_delete_single() {
@@ -364,6 +1007,26 @@ async fn safe_delete_single() {
     teardown_db(&db).await;
 }
 
+// safe_delete can run on another task, e.g. from a web handler that requires Send futures
+#[tokio::test]
+async fn safe_delete_spawned() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 10).await;
+
+    let task_db = db.clone();
+    tokio::spawn(async move { safe_delete(user, &task_db).await })
+        .await
+        .expect("Error joining task")
+        .expect("Error safe deleting");
+
+    assert_eq!(0, coll_count(&user_coll).await);
+    assert_eq!(0, coll_count(&post_coll).await);
+    teardown_db(&db).await;
+}
+
 // Comment owned by Post owned by User
 #[tokio::test]
 async fn safe_delete_post_comment() {
@@ -420,6 +1083,40 @@ async fn safe_delete_multiple_users() {
     teardown_db(&db).await;
 }
 
+// Batches smaller than the number of documents split each query without changing the result
+#[tokio::test]
+async fn safe_delete_small_batches() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+
+    insert_posts(&post_coll, 0, 10).await;
+    // User0 owns 50 comments on Post2, which User1 shares 50 more of
+    insert_comments(&comment_coll, 0, 2, 50).await;
+    insert_comments(&comment_coll, 1, 2, 50).await;
+
+    let options = DeleteOptions {
+        batch_size: 7,
+        ..Default::default()
+    };
+    safe_delete_with_options(user, &db, options)
+        .await
+        .expect("Error safe deleting");
+
+    assert_eq!(1, coll_count(&user_coll).await);
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(50, coll_count(&comment_coll).await);
+    let unlinked = comment_coll
+        .count_documents(doc! { "parent_post": { "$exists": false } }, None)
+        .await
+        .unwrap();
+    assert_eq!(50, unlinked);
+    teardown_db(&db).await;
+}
+
 // Comment owned by Post owned by User
 #[tokio::test]
 async fn safe_delete_multiple_owners() {
@@ -484,8 +1181,883 @@ async fn safe_delete_post() {
     assert_eq!(1, coll_count::<User>(&user_coll).await);
     assert_eq!(9, coll_count::<Post>(&post_coll).await);
 
-    // all comments belonging to User0 should be deleted + all comments belonging to Posts by User0
-    assert_eq!(100, coll_count::<Comment>(&comment_coll).await);
+    // the comments on Post2 are still owned by User0, so they are kept without a parent post
+    assert_eq!(200, coll_count::<Comment>(&comment_coll).await);
+    let orphaned = comment_coll
+        .count_documents(doc! { "parent_post": { "$exists": false } }, None)
+        .await
+        .expect("Error counting");
+    assert_eq!(100, orphaned);
+    teardown_db(&db).await;
+}
+
+// Comments owned by both User1 and a Post of User0 outlive User0 and go with User1
+#[tokio::test]
+async fn safe_delete_shared_ownership() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user0 = insert_user(&user_coll, 0).await;
+    let user1 = insert_user(&user_coll, 1).await;
+
+    // User0 owns 10 posts with ids in range [0, 9]
+    insert_posts(&post_coll, 0, 10).await;
+
+    // User1 owns 100 comments on Post2
+    insert_comments(&comment_coll, 1, 2, 100).await;
+
+    safe_delete(user0, &db).await.expect("Error safe deleting");
+
+    assert_eq!(1, coll_count(&user_coll).await);
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(100, coll_count(&comment_coll).await);
+    let unlinked = comment_coll
+        .count_documents(
+            doc! { "commented_by": 1, "parent_post": { "$exists": false } },
+            None,
+        )
+        .await
+        .expect("Error counting");
+    assert_eq!(100, unlinked);
+    // the unlinked comments still read back as comments
+    let comment = comment_coll
+        .find_one(doc! { "commented_by": 1 }, None)
+        .await
+        .expect("Error reading comment")
+        .expect("Comment not found");
+    assert_eq!(Some(1), comment.commented_by);
+    assert_eq!(None, comment.parent_post);
+
+    safe_delete(user1, &db).await.expect("Error safe deleting");
+
+    assert_eq!(0, coll_count(&user_coll).await);
+    assert_eq!(0, coll_count(&comment_coll).await);
+    teardown_db(&db).await;
+}
+
+// The transactional cascade is refused on a standalone server. On a replica set, a failure
+// after the first deletes (here, a receipt the audit collection rejects) rolls every one of
+// them back.
+#[tokio::test]
+async fn safe_delete_transaction_all_or_nothing() {
+    let client = init_test_client().await;
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user_id = 0;
+    let user = insert_user(&user_coll, user_id).await;
+
+    insert_posts(&post_coll, user_id, 10).await;
+    insert_comments(&comment_coll, user_id, 2, 100).await;
+
+    let hello = db
+        .run_command(doc! { "hello": 1 }, None)
+        .await
+        .expect("Error running hello");
+    let replica_set = hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid");
+    if !replica_set {
+        match safe_delete_transaction(user, &client, &db).await {
+            Err(Error::TransactionsUnsupported { database }) => assert_eq!(db.name(), database),
+            Err(e) => panic!("expected TransactionsUnsupported, got {}", e),
+            Ok(_) => panic!("expected TransactionsUnsupported, got a receipt"),
+        }
+    } else {
+        let validator = doc! { "rejected": { "$exists": true } };
+        let create_options = CreateCollectionOptions::builder()
+            .validator(validator)
+            .build();
+        db.create_collection("rejected_receipts", create_options)
+            .await
+            .expect("Error creating audit collection");
+        let options = DeleteOptions {
+            audit_collection: Some("rejected_receipts".to_string()),
+            ..Default::default()
+        };
+        match safe_delete_transaction_with_options(user, &client, &db, options).await {
+            Err(Error::Mongo(_)) => {}
+            Err(e) => panic!("expected the audit insert to fail, got {}", e),
+            Ok(_) => panic!("expected the audit insert to fail, got a receipt"),
+        }
+    }
+
+    assert_eq!(1, coll_count(&user_coll).await);
+    assert_eq!(10, coll_count(&post_coll).await);
+    assert_eq!(100, coll_count(&comment_coll).await);
+    teardown_db(&db).await;
+}
+
+// The receipt of a deletion counts what was deleted and is stored in the audit collection
+#[tokio::test]
+async fn safe_delete_audit_receipt() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 10).await;
+    insert_comments(&comment_coll, 0, 2, 100).await;
+    let subject = user.index_filter().unwrap().get("id").cloned();
+
+    let options = DeleteOptions {
+        audit_collection: Some("deletion_receipts".to_string()),
+        ..Default::default()
+    };
+    let receipt = safe_delete_with_options(user, &db, options)
+        .await
+        .expect("Error safe deleting");
+
+    assert_eq!(Some(receipt.subject.clone()), subject);
+    assert_eq!(User::collection_name(), receipt.collection);
+    assert_eq!(DeleteMode::Hard, receipt.mode);
+    let graph = load_graph().expect("Error loading graph");
+    assert_eq!(graph_version(&graph), receipt.graph_version);
+    let counts: Vec<(&str, u64)> = receipt
+        .deleted_counts
+        .iter()
+        .map(|(collection_name, count)| (collection_name.as_str(), *count))
+        .collect();
+    assert_eq!(vec![("comments", 100), ("posts", 10), ("users", 1)], counts);
+    assert_eq!(111, receipt.total_count());
+
+    let stored = db
+        .collection::<DeletionReceipt>("deletion_receipts")
+        .find_one(doc! {}, None)
+        .await
+        .unwrap()
+        .expect("Receipt should be stored");
+    assert_eq!(receipt, stored);
+    teardown_db(&db).await;
+}
+
+// Verification finds nothing after a deletion, and finds a Post recreated for the deleted
+// User along with the Comments on it
+#[tokio::test]
+async fn verify_deleted_finds_leftovers() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 10).await;
+    insert_comments(&comment_coll, 0, 2, 100).await;
+
+    let options = DeleteOptions {
+        verify: true,
+        ..Default::default()
+    };
+    let receipt = safe_delete_with_options(user, &db, options)
+        .await
+        .expect("Error safe deleting");
+    assert!(receipt.verified);
+    let verification = verify_deleted::<User>(0, &db)
+        .await
+        .expect("Error verifying");
+    assert!(verification.is_clean());
+
+    // A Post of User0 written after the deletion, with a Comment of another User on it
+    insert_posts(&post_coll, 0, 1).await;
+    insert_comments(&comment_coll, 1, 0, 1).await;
+    let verification = verify_deleted::<User>(0, &db)
+        .await
+        .expect("Error verifying");
+    assert_eq!(2, verification.total_count());
+    let collections: Vec<&String> = verification.remaining.keys().collect();
+    assert_eq!(vec!["comments", "posts"], collections);
+    teardown_db(&db).await;
+}
+
+// Posts of a User deleted without safe_delete are orphans; deleting them only unlinks the
+// Comments that another User still owns
+#[tokio::test]
+async fn find_and_delete_orphans() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    insert_user(&user_coll, 0).await;
+    // User5 was deleted along with nothing it owned
+    insert_posts(&post_coll, 5, 3).await;
+    insert_comments(&comment_coll, 0, 1, 2).await;
+
+    let report = find_orphans(&db).await.expect("Error finding orphans");
+    assert_eq!(3, report.total_count());
+    assert_eq!(Some(3), report.orphans.get("posts").map(Vec::len));
+    assert_eq!(3, coll_count(&post_coll).await);
+
+    let report = delete_orphans(&db).await.expect("Error deleting orphans");
+    assert_eq!(Some(&3), report.deleted_counts.get("posts"));
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(2, coll_count(&comment_coll).await);
+    let report = find_orphans(&db).await.expect("Error finding orphans");
+    assert_eq!(0, report.total_count());
+    teardown_db(&db).await;
+}
+
+// An orphan whose deletion is restricted is reported, and the other orphans are deleted
+#[tokio::test]
+async fn delete_orphans_past_failures() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let flag_coll = db.collection::<Flag>(Flag::collection_name());
+    insert_user(&user_coll, 0).await;
+    // User5 was deleted along with nothing it owned
+    insert_posts(&post_coll, 5, 3).await;
+    // User0 flagged Post1, which restricts its deletion
+    let flag = Flag {
+        id: 0,
+        flagged_by: 0,
+        post_id: 1,
+    };
+    flag_coll
+        .insert_one(flag, None)
+        .await
+        .expect("Failed to insert flag");
+
+    let report = delete_orphans(&db).await.expect("Error deleting orphans");
+    assert_eq!(Some(&2), report.deleted_counts.get("posts"));
+    assert_eq!(1, report.failures.len());
+    assert_eq!(Post::collection_name(), report.failures[0].collection);
+    let remaining = post_coll
+        .find_one(doc! {}, None)
+        .await
+        .unwrap()
+        .expect("Post1 should remain");
+    assert_eq!(1, remaining.id);
+    teardown_db(&db).await;
+}
+
+// Deleting an orphan pulls the embedded entries referencing it even when nothing else
+// references its owner index, and no index map says to fetch it, as with the graph files
+// of the CLI
+#[tokio::test]
+async fn delete_orphans_pulls_embedded_entries() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let photo_coll = db.collection::<Photo>(Photo::collection_name());
+    // User5 was deleted along with nothing it owned
+    insert_posts(&post_coll, 5, 3).await;
+    // Post0 and Post1 are tagged in both photos
+    insert_photos(&photo_coll).await;
+
+    let mut graph = GraphMap::new();
+    graph.add_edge(
+        "posts",
+        "users",
+        OwnEdge {
+            owner_index: "id",
+            owned_field: "posted_by",
+            kind: EdgeKind::Owned,
+        },
+    );
+    let embedded = [(
+        "photos",
+        EmbeddedOwnedBy {
+            owner_collection: "posts",
+            owner_index: "id",
+            array_field: "tagged",
+            element_field: None,
+        },
+    )];
+    let report = delete_orphans_with_graph(&graph, &embedded, &db)
+        .await
+        .expect("Error deleting orphans");
+    assert_eq!(Some(&3), report.deleted_counts.get("posts"));
+    assert_eq!(0, coll_count(&post_coll).await);
+    let mut photos = photo_coll.find(doc! {}, None).await.unwrap();
+    while let Some(photo) = photos.next().await {
+        assert!(photo.unwrap().tagged.is_empty());
+    }
+    teardown_db(&db).await;
+}
+
+#[test]
+fn embedded_file_matches_registry() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target/mongowner/safe_delete_test/embedded.json");
+    let mut contents = String::new();
+    let embedded = load_embedded_file(&path, &mut contents).expect("Error loading embedded");
+    let describe = |(collection_name, embedded): &(&str, EmbeddedOwnedBy)| {
+        format!(
+            "{} {}.{:?} -> {}.{}",
+            collection_name,
+            embedded.array_field,
+            embedded.element_field,
+            embedded.owner_collection,
+            embedded.owner_index
+        )
+    };
+    let mut from_file: Vec<String> = embedded.iter().map(describe).collect();
+    let mut registered: Vec<String> = load_embedded().iter().map(describe).collect();
+    from_file.sort();
+    registered.sort();
+    assert_eq!(registered, from_file);
+}
+
+// The methods generated by the derive find and delete documents by their index
+#[tokio::test]
+async fn generated_delete_helpers() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let membership_coll = db.collection::<Membership>(Membership::collection_name());
+    insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_posts(&post_coll, 0, 10).await;
+    let membership = Membership {
+        org: "brown".to_string(),
+        seat: 3,
+        member: ObjectId::new(),
+    };
+    membership_coll
+        .insert_one(&membership, None)
+        .await
+        .expect("Failed to insert membership");
+
+    let user = User::find_by_index(1, &db)
+        .await
+        .expect("Error finding user")
+        .expect("User1 should exist");
+    assert_eq!(1, user.id);
+    user.delete_cascade(&db).await.expect("Error deleting user");
+    assert!(User::find_by_index(1, &db).await.unwrap().is_none());
+
+    let receipt = User::delete_by_index(0, &db)
+        .await
+        .expect("Error deleting user")
+        .expect("User0 should exist");
+    assert_eq!(11, receipt.total_count());
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert!(User::delete_by_index(0, &db).await.unwrap().is_none());
+
+    // Deleting a user that doesn't exist leaves what still references it alone
+    insert_posts(&post_coll, 9, 2).await;
+    assert!(User::delete_by_index(9, &db).await.unwrap().is_none());
+    assert_eq!(2, coll_count(&post_coll).await);
+
+    let found = Membership::find_by_index(("brown".to_string(), 3), &db)
+        .await
+        .expect("Error finding membership")
+        .expect("Membership should exist");
+    assert_eq!(membership.member, found.member);
+    teardown_db(&db).await;
+}
+
+// A Mongowner context and its clones share the graph it loaded and delete like the free
+// functions
+#[tokio::test]
+async fn context_delete_post_comment() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let context =
+        Mongowner::new(init_test_client().await, db.name()).expect("Error building context");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_posts(&post_coll, 0, 10).await;
+    insert_comments(&comment_coll, 1, 2, 100).await;
+
+    let worker = context.clone();
+    assert!(std::ptr::eq(context.graph(), worker.graph()));
+    let plan = worker
+        .plan_delete(&user)
+        .await
+        .expect("Error planning delete");
+    let receipt = worker.safe_delete(user).await.expect("Error deleting user");
+    assert_eq!(plan.total_count(), receipt.total_count() as usize);
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(0, coll_count(&comment_coll).await);
+    assert!(context.verify_deleted::<User>(0).await.unwrap().is_clean());
+
+    assert!(context.delete_by_index::<User>(1).await.unwrap().is_some());
+    assert!(context.find_by_index::<User>(1).await.unwrap().is_none());
+    teardown_db(&db).await;
+}
+
+// A deletion with progress reports the resolved cascade, then every batch it deletes, and
+// completes with the level of the root
+#[tokio::test]
+async fn safe_delete_progress_events() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 10).await;
+    insert_comments(&comment_coll, 0, 2, 20).await;
+
+    let options = DeleteOptions {
+        batch_size: 3,
+        ..DeleteOptions::default()
+    };
+    let (events, deletion) = safe_delete_with_progress(user, &db, options, Cancellation::new());
+    let (events, receipt) = futures::join!(events.collect::<Vec<_>>(), deletion);
+    let receipt = receipt.expect("Error deleting user");
+
+    let expected: BTreeMap<String, u64> = BTreeMap::from([
+        ("comments".into(), 20),
+        ("posts".into(), 10),
+        ("users".into(), 1),
+    ]);
+    assert_eq!(
+        Some(&DeleteEvent::Resolved {
+            counts: expected.clone()
+        }),
+        events.first()
+    );
+    let mut deleted: BTreeMap<String, u64> = BTreeMap::new();
+    for event in &events {
+        if let DeleteEvent::DocumentsDeleted { collection, count } = event {
+            *deleted.entry(collection.clone()).or_default() += count;
+        }
+    }
+    assert_eq!(expected, deleted);
+    assert_eq!(receipt.deleted_counts, deleted);
+    // 20 comments in batches of 3
+    assert_eq!(
+        7,
+        events
+            .iter()
+            .filter(|event| matches!(event, DeleteEvent::DocumentsDeleted { collection, .. } if collection == "comments"))
+            .count()
+    );
+    assert!(events.contains(&DeleteEvent::LevelCompleted { depth: 1 }));
+    assert_eq!(
+        Some(&DeleteEvent::LevelCompleted { depth: 0 }),
+        events.last()
+    );
+    teardown_db(&db).await;
+}
+
+// Cancelling a deletion stops it between batches, leaving the owners of what was deleted
+// in place and reporting how much was deleted
+#[tokio::test]
+async fn safe_delete_cancelled() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 10).await;
+
+    let options = DeleteOptions {
+        batch_size: 1,
+        ..DeleteOptions::default()
+    };
+    let cancellation = Cancellation::new();
+    let (mut events, deletion) =
+        safe_delete_with_progress(user, &db, options, cancellation.clone());
+    let cancel = async {
+        while let Some(event) = events.next().await {
+            if let DeleteEvent::DocumentsDeleted { .. } = event {
+                cancellation.cancel();
+            }
+        }
+    };
+    let (_, result) = futures::join!(cancel, deletion);
+
+    let deleted_counts = match result {
+        Err(Error::Cancelled { deleted_counts }) => deleted_counts,
+        other => panic!("Expected a cancelled deletion, got {:?}", other.map(|_| ())),
+    };
+    let deleted_posts = deleted_counts["posts"];
+    assert!(deleted_posts > 0 && deleted_posts < 10);
+    assert!(!deleted_counts.contains_key("users"));
+    assert_eq!(10 - deleted_posts, coll_count(&post_coll).await);
+    assert_eq!(1, coll_count(&user_coll).await);
+    teardown_db(&db).await;
+}
+
+// A deletion job whose worker died after deleting the subject is resumed once its lease
+// expired, and completes by deleting what the subject owned
+#[tokio::test]
+async fn deletion_job_resumed() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_posts(&post_coll, 0, 10).await;
+    insert_comments(&comment_coll, 0, 2, 20).await;
+
+    let id = enqueue_delete(&user, &db, DeleteOptions::default())
+        .await
+        .expect("Error enqueueing delete");
+    let job = find_job(id, &db).await.unwrap().expect("Job should exist");
+    assert_eq!(JobStatus::Pending, job.status);
+
+    // The worker that claimed the job crashed after the subject was gone
+    db.collection::<Document>(JOBS_COLLECTION)
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$set": {
+                "status": "running",
+                "worker": "crashed",
+                "lease_expires_at": DateTime::from_millis(0),
+            }, "$inc": { "attempts": 1 } },
+            None,
+        )
+        .await
+        .expect("Failed to claim job");
+    user_coll
+        .delete_one(doc! { "id": 0 }, None)
+        .await
+        .expect("Failed to delete user");
+
+    let worker = WorkerOptions::default();
+    let jobs = run_pending_jobs(&db, &worker)
+        .await
+        .expect("Error running jobs");
+    assert_eq!(1, jobs.len());
+    let job = &jobs[0];
+    assert_eq!(JobStatus::Completed, job.status);
+    assert_eq!(2, job.attempts);
+    assert_eq!(None, job.worker);
+    assert_eq!(
+        BTreeMap::from([("comments".to_string(), 20), ("posts".to_string(), 10)]),
+        job.deleted_counts
+    );
+    assert!(job.receipt.is_some());
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(0, coll_count(&comment_coll).await);
+    assert!(run_next_job(&db, &worker).await.unwrap().is_none());
+    teardown_db(&db).await;
+}
+
+// A deletion job that keeps failing is retried until it runs out of attempts
+#[tokio::test]
+async fn deletion_job_failed() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let flag_coll = db.collection::<Flag>(Flag::collection_name());
+    let user = insert_user(&user_coll, 0).await;
+    insert_user(&user_coll, 1).await;
+    insert_posts(&post_coll, 0, 1).await;
+    flag_coll
+        .insert_one(
+            Flag {
+                id: 0,
+                flagged_by: 1,
+                post_id: 0,
+            },
+            None,
+        )
+        .await
+        .expect("Failed to insert flag");
+
+    enqueue_delete(&user, &db, DeleteOptions::default())
+        .await
+        .expect("Error enqueueing delete");
+    let worker = WorkerOptions {
+        max_attempts: 2,
+        ..WorkerOptions::default()
+    };
+    let jobs = run_pending_jobs(&db, &worker)
+        .await
+        .expect("Error running jobs");
+    let statuses: Vec<JobStatus> = jobs.iter().map(|job| job.status).collect();
+    assert_eq!(vec![JobStatus::Pending, JobStatus::Failed], statuses);
+    assert!(jobs[1].error.is_some());
+    assert_eq!(2, coll_count(&user_coll).await);
+    assert_eq!(1, coll_count(&post_coll).await);
+    teardown_db(&db).await;
+}
+
+// Subjects are deleted by their index values, or by a filter, without being loaded first
+#[tokio::test]
+async fn safe_delete_by_index_values() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    for user_id in 0..5 {
+        insert_user(&user_coll, user_id).await;
+    }
+    insert_posts(&post_coll, 0, 10).await;
+
+    let receipt = safe_delete_by_id::<User>(0, &db)
+        .await
+        .expect("Error deleting user");
+    assert!(receipt.subject_deleted());
+    assert_eq!(11, receipt.total_count());
+    assert_eq!(0, coll_count(&post_coll).await);
+    let receipt = safe_delete_by_id::<User>(0, &db)
+        .await
+        .expect("Error deleting user");
+    assert!(!receipt.subject_deleted());
+
+    // Bulk deletions run a single cascade, with a single receipt
+    let receipt = safe_delete_by_ids::<User>([1, 2], &db)
+        .await
+        .expect("Error deleting users");
+    assert_eq!(
+        Bson::Array(vec![Bson::Int64(1), Bson::Int64(2)]),
+        receipt.subject
+    );
+    assert_eq!(Some(&2), receipt.deleted_counts.get("users"));
+    assert_eq!(2, coll_count(&user_coll).await);
+
+    let options = DeleteOptions {
+        mode: DeleteMode::Soft,
+        ..Default::default()
+    };
+    let receipt =
+        safe_delete_matching_with_options::<User>(doc! { "id": { "$gte": 3 } }, &db, options)
+            .await
+            .expect("Error deleting users");
+    assert_eq!(DeleteMode::Soft, receipt.mode);
+    assert_eq!(Some(&2), receipt.deleted_counts.get("users"));
+    assert_eq!(2, coll_count(&user_coll).await);
+
+    // A user without an index can't be deleted by a filter
+    db.collection::<Document>(User::collection_name())
+        .insert_one(doc! { "username": "anonymous" }, None)
+        .await
+        .expect("Failed to insert user");
+    let result = safe_delete_matching::<User>(doc! { "username": "anonymous" }, &db).await;
+    assert!(matches!(result, Err(Error::MissingIndexField { .. })));
+    assert_eq!(3, coll_count(&user_coll).await);
+    teardown_db(&db).await;
+}
+
+// Planning the deletion of a User reports its Posts and the Comments on them without
+// deleting anything
+#[tokio::test]
+async fn plan_delete_post_comment() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user_id = 0;
+    let user = insert_user(&user_coll, user_id).await;
+
+    // User0 owns 10 posts with ids in range [0, 9]
+    insert_posts(&post_coll, user_id, 10).await;
+
+    // User1 owns 100 comments on Post2
+    insert_comments(&comment_coll, 1, 2, 100).await;
+
+    let plan = plan_delete(&user, &db)
+        .await
+        .expect("Error planning delete");
+
+    let users = &plan.collections[User::collection_name()];
+    assert_eq!((0, 1), (users.depth, users.count()));
+    let posts = &plan.collections[Post::collection_name()];
+    assert_eq!((1, 10), (posts.depth, posts.count()));
+    let comments = &plan.collections[Comment::collection_name()];
+    assert_eq!((2, 100), (comments.depth, comments.count()));
+    assert_eq!(111, plan.total_count());
+
+    assert_eq!(1, coll_count(&user_coll).await);
+    assert_eq!(10, coll_count(&post_coll).await);
+    assert_eq!(100, coll_count(&comment_coll).await);
+    teardown_db(&db).await;
+}
+
+#[tokio::test]
+async fn export_subject_post_comment() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user_id = 0;
+    let user = insert_user(&user_coll, user_id).await;
+    insert_user(&user_coll, 1).await;
+
+    // User0 owns 10 posts with ids in range [0, 9]
+    insert_posts(&post_coll, user_id, 10).await;
+
+    // User1 owns 100 comments on Post2, so shares them with User0
+    insert_comments(&comment_coll, 1, 2, 100).await;
+
+    let export = export_subject(&user, &db)
+        .await
+        .expect("Error exporting subject");
+
+    assert_eq!(1, export.collections[User::collection_name()].len());
+    assert_eq!(10, export.collections[Post::collection_name()].len());
+    assert_eq!(100, export.collections[Comment::collection_name()].len());
+    assert_eq!(111, export.total_count());
+
+    let json = export.to_json();
+    assert_eq!(10, json[Post::collection_name()].as_array().unwrap().len());
+
+    let archive = export.to_bson_archive().expect("Error encoding archive");
+    let mut reader = archive.as_slice();
+    let mut archived = 0;
+    while !reader.is_empty() {
+        mongodb::bson::Document::from_reader(&mut reader).expect("Error reading archive");
+        archived += 1;
+    }
+    assert_eq!(111, archived);
+
+    // Exporting leaves the database untouched
+    assert_eq!(2, coll_count(&user_coll).await);
+    assert_eq!(10, coll_count(&post_coll).await);
+    teardown_db(&db).await;
+}
+
+// Documents are matched by their stored index values, whether that is an ObjectId `_id`, a
+// Uuid or several fields of a composite index
+#[tokio::test]
+async fn safe_delete_native_and_composite_indexes() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let member_coll = db.collection::<Member>(Member::collection_name());
+    let session_coll = db.collection::<Session>(Session::collection_name());
+    let membership_coll = db.collection::<Membership>(Membership::collection_name());
+    let member = Member {
+        id: ObjectId::new(),
+        name: Name().fake(),
+    };
+    member_coll
+        .insert_one(&member, None)
+        .await
+        .expect("Failed to insert member");
+    let sessions: Vec<Session> = (0..5)
+        .map(|_| Session {
+            token: Uuid::new(),
+            member: member.id,
+        })
+        .collect();
+    session_coll
+        .insert_many(sessions, None)
+        .await
+        .expect("Failed to insert sessions");
+    let memberships: Vec<Membership> = (0..3)
+        .map(|seat| Membership {
+            org: "acme".to_string(),
+            seat,
+            member: member.id,
+        })
+        .collect();
+    membership_coll
+        .insert_many(&memberships, None)
+        .await
+        .expect("Failed to insert memberships");
+
+    // Deleting by a composite index removes exactly the matching document
+    let plan = plan_delete(&memberships[1], &db)
+        .await
+        .expect("Error planning delete");
+    let planned = &plan.collections[Membership::collection_name()];
+    assert_eq!(vec!["org", "seat"], planned.index_names);
+    assert_eq!(1, planned.count());
+    safe_delete(memberships[1].clone(), &db)
+        .await
+        .expect("Error safe deleting membership");
+    assert_eq!(2, coll_count(&membership_coll).await);
+
+    safe_delete(member, &db)
+        .await
+        .expect("Error safe deleting member");
+    assert_eq!(0, coll_count(&member_coll).await);
+    assert_eq!(0, coll_count(&session_coll).await);
+    assert_eq!(0, coll_count(&membership_coll).await);
+    teardown_db(&db).await;
+}
+
+// Soft deletion tombstones the whole subtree, and purging only removes the tombstones whose
+// retention period has passed
+#[tokio::test]
+async fn soft_delete_then_purge() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let account_coll = db.collection::<Account>(Account::collection_name());
+    let invoice_coll = db.collection::<Invoice>(Invoice::collection_name());
+    let account = Account {
+        id: 0,
+        email: FreeEmail().fake(),
+    };
+    account_coll
+        .insert_one(&account, None)
+        .await
+        .expect("Failed to insert account");
+    insert_invoices(&invoice_coll, 0, 5).await;
+
+    let options = DeleteOptions {
+        mode: DeleteMode::Soft,
+        ..Default::default()
+    };
+    safe_delete_with_options(account, &db, options)
+        .await
+        .expect("Error soft deleting account");
+
+    assert_eq!(1, coll_count(&account_coll).await);
+    assert_eq!(5, coll_count(&invoice_coll).await);
+    let tombstoned = doc! { DELETED_AT: { "$exists": true } };
+    assert_eq!(
+        1,
+        account_coll
+            .count_documents(tombstoned.clone(), None)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        5,
+        invoice_coll
+            .count_documents(tombstoned, None)
+            .await
+            .unwrap()
+    );
+
+    // Invoices have no retention period of their own, but are kept as long as the account,
+    // for 30 days
+    let purged = purge_expired(&db).await.expect("Error purging");
+    assert_eq!(0, purged);
+    assert_eq!(1, coll_count(&account_coll).await);
+    assert_eq!(5, coll_count(&invoice_coll).await);
+
+    // Once the account expires, so do its invoices
+    let expired = doc! { "$set": { PURGE_AFTER: DateTime::now() } };
+    account_coll
+        .update_many(doc! {}, expired.clone(), None)
+        .await
+        .unwrap();
+    invoice_coll
+        .update_many(doc! {}, expired, None)
+        .await
+        .unwrap();
+    let purged = purge_expired(&db).await.expect("Error purging");
+    assert_eq!(6, purged);
+    assert_eq!(0, coll_count(&account_coll).await);
+    assert_eq!(0, coll_count(&invoice_coll).await);
+    teardown_db(&db).await;
+}
+
+// A tombstoned owner doesn't keep a shared document alive: deleting its other owner deletes
+// the document instead of leaving it to be orphaned by the purge
+#[tokio::test]
+async fn soft_delete_shared_then_purge() {
+    let db = init_test_db().await.expect("Error with init test db");
+    let user_coll = db.collection::<User>(User::collection_name());
+    let post_coll = db.collection::<Post>(Post::collection_name());
+    let comment_coll = db.collection::<Comment>(Comment::collection_name());
+    let user0 = insert_user(&user_coll, 0).await;
+    let user1 = insert_user(&user_coll, 1).await;
+
+    // User1 owns 100 comments on Post2 of User0
+    insert_posts(&post_coll, 0, 10).await;
+    insert_comments(&comment_coll, 1, 2, 100).await;
+
+    let options = DeleteOptions {
+        mode: DeleteMode::Soft,
+        ..Default::default()
+    };
+    safe_delete_with_options(user1, &db, options)
+        .await
+        .expect("Error soft deleting user");
+    // the comments are still owned by Post2
+    assert_eq!(100, coll_count(&comment_coll).await);
+
+    safe_delete(user0, &db).await.expect("Error safe deleting");
+    assert_eq!(0, coll_count(&post_coll).await);
+    assert_eq!(0, coll_count(&comment_coll).await);
+
+    let purged = purge_expired(&db).await.expect("Error purging");
+    assert_eq!(1, purged);
+    assert_eq!(0, coll_count(&user_coll).await);
     teardown_db(&db).await;
 }
 
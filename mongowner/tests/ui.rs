@@ -0,0 +1,7 @@
+// Schemas the derive must reject, each with the error it reports, and the ones it must accept
+#[test]
+fn schema_ui() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+    cases.pass("tests/ui/pass/*.rs");
+}
@@ -0,0 +1,14 @@
+use mongowner::Schema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    invited_by: u32,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use mongowner::Schema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use mongowner::Schema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    id: u32,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use mongowner::Schema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+}
+
+fn main() {}
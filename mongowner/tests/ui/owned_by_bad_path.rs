@@ -0,0 +1,26 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Meta {
+    author_id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+    #[owned_by(users, id, path = "$author_id")]
+    meta: Meta,
+}
+
+fn main() {}
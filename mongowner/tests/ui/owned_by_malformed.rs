@@ -0,0 +1,21 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+    #[owned_by(users)]
+    posted_by: u32,
+}
+
+fn main() {}
@@ -0,0 +1,22 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+    name: String,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+    #[owned_by(users, name)]
+    posted_by: String,
+}
+
+fn main() {}
@@ -0,0 +1,37 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    posted_by: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Meta {
+    author_id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(comments)]
+pub struct Comment {
+    #[index]
+    id: u32,
+    #[owned_by(users, id, path = "author_id")]
+    meta: Meta,
+    #[owned_by(posts, id)]
+    parent_post: Option<u32>,
+}
+
+fn main() {}
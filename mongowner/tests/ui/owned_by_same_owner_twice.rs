@@ -0,0 +1,23 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(messages)]
+pub struct Message {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    sender: Option<u32>,
+    #[owned_by(users, id)]
+    recipient: Option<u32>,
+}
+
+fn main() {}
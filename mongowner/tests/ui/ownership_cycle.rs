@@ -0,0 +1,32 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(folders)]
+pub struct Folder {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    owner: Option<u32>,
+    #[owned_by(files, id)]
+    cover: Option<u32>,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(files)]
+pub struct File {
+    #[index]
+    id: u32,
+    #[owned_by(folders, id)]
+    folder: u32,
+}
+
+fn main() {}
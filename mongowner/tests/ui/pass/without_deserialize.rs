@@ -0,0 +1,20 @@
+use mongowner::{Schema, Schemable, SchemableExt};
+use serde::Serialize;
+
+// Only finding documents needs Deserialize, so a struct that is never read back derives
+// Schema without it
+#[derive(Schema, Serialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[allow(dead_code)]
+async fn delete(user: User, db: &mongowner::mongo::Database) {
+    let _ = user.delete_cascade(db).await;
+    let _ = User::delete_by_index(0, db).await;
+}
+
+fn main() {}
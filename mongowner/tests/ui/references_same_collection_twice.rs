@@ -0,0 +1,24 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(messages)]
+pub struct Message {
+    #[index]
+    id: u32,
+    #[references(users, id, on_delete = set_null)]
+    sender: Option<u32>,
+    #[references(users, id, on_delete = set_null)]
+    recipient: Option<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,33 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+    name: String,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    posted_by: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(bookmarks)]
+pub struct Bookmark {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    saved_by: u32,
+    #[references(posts, id, on_delete = set_null)]
+    post_id: u32,
+}
+
+fn main() {}
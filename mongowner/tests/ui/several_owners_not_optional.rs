@@ -0,0 +1,32 @@
+use mongowner::{Schema, Schemable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User {
+    #[index]
+    id: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(posts)]
+pub struct Post {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    posted_by: u32,
+}
+
+#[derive(Schema, Serialize, Deserialize)]
+#[collection(comments)]
+pub struct Comment {
+    #[index]
+    id: u32,
+    #[owned_by(users, id)]
+    commented_by: Option<u32>,
+    #[owned_by(posts, id)]
+    parent_post: u32,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use mongowner::Schema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Serialize, Deserialize)]
+#[data_subject]
+#[collection(users)]
+pub struct User(u32);
+
+fn main() {}